@@ -1,34 +1,79 @@
 use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
     sync::Arc,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use anyhow::{Error, anyhow};
+use anyhow::{Context, Error, anyhow};
 use cln_plugin::Plugin;
 use cln_rpc::{
     ClnRpc,
     model::{
-        requests::{ListchannelsRequest, ListnodesRequest, ListpeerchannelsRequest, PingRequest},
-        responses::ListnodesNodesAddressesType,
+        requests::{
+            GetinfoRequest,
+            ListchannelsRequest,
+            ListfundsRequest,
+            ListnodesRequest,
+            ListpeerchannelsRequest,
+            ListpeersRequest,
+            PingRequest,
+        },
+        responses::{ListfundsOutputsStatus, ListnodesNodes, ListnodesNodesAddressesType},
     },
     primitives::{Amount, ChannelState, PublicKey},
 };
+use parking_lot::Mutex;
 use serde_json::{Value, json};
-use tokio::time::{self, timeout};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, lookup_host},
+    process::Command,
+    sync::Semaphore,
+    task::JoinSet,
+    time::{self, timeout},
+};
 
 use crate::{
+    OPT_EXT_COMMAND,
+    OPT_MEMPOOL_URL,
+    PLUGIN_NAME,
+    config::{GEO_DB_FILE, read_cidr_file},
     notify::notify,
+    parser::{cidr_files, rule_names},
     structs::{
         AmbossResponse,
+        Capability,
         ChannelFlags,
+        ChannelParams,
+        ClusterInfo,
+        Config,
+        ConnectionInfo,
+        DataSource,
+        GeoDb,
+        GeoInfo,
+        GraphCache,
+        MempoolNode,
         NotifyVerbosity,
         OneMl,
         OpeningInfo,
+        OwnNode,
         PeerData,
-        PeerDataCache,
         PeerInfo,
+        PingStats,
         PluginState,
+        ReachabilityInfo,
+        RuleNames,
+        RuleValue,
+    },
+    tasks::{
+        apply_uptime_history,
+        refresh_alias_cache,
+        refresh_graph_cache,
+        refresh_history_cache,
     },
 };
 
@@ -178,7 +223,126 @@ async fn get_amboss_data(
     }
 }
 
-async fn get_gossip_data(rpc_path: PathBuf, pubkey: PublicKey) -> Result<PeerInfo, Error> {
+async fn get_ext_data(
+    ext_command: String,
+    input: Value,
+    ext_semaphore: Arc<Semaphore>,
+) -> Result<HashMap<String, RuleValue>, Error> {
+    let _permit = ext_semaphore.acquire().await?;
+    log::debug!("ext_data: start");
+
+    let mut child = Command::new(&ext_command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context(format!("Could not start {ext_command}"))?;
+    let mut stdin = child.stdin.take().unwrap();
+    if let Err(e) = stdin.write_all(input.to_string().as_bytes()).await {
+        log::debug!("ext_data: could not write to stdin: {e}");
+    }
+    drop(stdin);
+    let output = child.wait_with_output().await?;
+    log::debug!("ext_data: done");
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{ext_command} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let json: Value = serde_json::from_slice(&output.stdout)
+        .context(format!("{ext_command} did not return valid JSON"))?;
+    log::debug!("ext response: {json:#?}");
+    let Value::Object(variables) = json else {
+        return Err(anyhow!("{ext_command} did not return a JSON object"));
+    };
+
+    let mut ext_data = HashMap::new();
+    for (name, value) in variables {
+        let name = name.to_ascii_lowercase();
+        let name = if name.starts_with("ext_") {
+            name
+        } else {
+            format!("ext_{name}")
+        };
+        let value = match value {
+            Value::Bool(b) => RuleValue::Integer(u64::from(b)),
+            Value::Number(n) => RuleValue::Integer(n.as_u64().ok_or_else(|| {
+                anyhow!("{ext_command} returned {name} that is not a positive integer")
+            })?),
+            Value::String(s) => RuleValue::Text(s),
+            Value::Null => continue,
+            _ => {
+                return Err(anyhow!(
+                    "{ext_command} returned {name} with an unsupported type"
+                ));
+            }
+        };
+        ext_data.insert(name, value);
+    }
+    Ok(ext_data)
+}
+
+async fn get_mempool_data(
+    pubkey: PublicKey,
+    mempool_url: String,
+    mempool_lock: Arc<tokio::sync::Mutex<u128>>,
+) -> Result<MempoolNode, Error> {
+    let mut last_api_call = mempool_lock.lock().await;
+    log::debug!("mempool_data: start");
+
+    let mut unix_now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    while (unix_now_ms - *last_api_call) <= 250 {
+        time::sleep(Duration::from_millis(50)).await;
+        unix_now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+    }
+
+    let response = bitreq::get(format!("{mempool_url}/api/v1/lightning/nodes/{pubkey}"))
+        .send_async()
+        .await?;
+
+    *last_api_call = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    log::debug!("mempool_data: done");
+
+    match response.status_code {
+        200 => {
+            let json: Value = response.json()?;
+            log::debug!("mempool response: {json:#?}");
+            let mempool_node: MempoolNode = serde_json::from_value(json)?;
+            Ok(mempool_node)
+        }
+        404 => Ok(MempoolNode::default()),
+        _ => {
+            log::debug!(
+                "mempool_data: bad API response, status:{}",
+                response.status_code
+            );
+            Err(anyhow!(
+                "mempool: bad API response, status:{}",
+                response.status_code
+            ))
+        }
+    }
+}
+
+async fn get_gossip_data(
+    rpc_path: PathBuf,
+    pubkey: PublicKey,
+    graph_cache: Arc<Mutex<GraphCache>>,
+    alias_cache: Arc<Mutex<HashMap<PublicKey, String>>>,
+) -> Result<PeerInfo, Error> {
     log::debug!("gossip_data: start");
     let mut list_node_rpc = ClnRpc::new(&rpc_path).await?;
 
@@ -199,52 +363,250 @@ async fn get_gossip_data(rpc_path: PathBuf, pubkey: PublicKey) -> Result<PeerInf
             .await
     });
 
+    let mut getinfo_rpc = ClnRpc::new(&rpc_path).await?;
+    let getinfo_task =
+        tokio::spawn(async move { getinfo_rpc.call_typed(&GetinfoRequest {}).await });
+
+    let mut our_channels_rpc = ClnRpc::new(&rpc_path).await?;
+    let our_channels_task = tokio::spawn(async move {
+        our_channels_rpc
+            .call_typed(&ListpeerchannelsRequest {
+                id: None,
+                short_channel_id: None,
+                channel_id: None,
+            })
+            .await
+    });
+
     let list_nodes = list_node_task.await??.nodes;
-    let list_node = if let Some(node) = list_nodes.first() {
+    let list_node = list_nodes.first();
+    if let Some(node) = list_node {
         log::debug!("{node:?}");
-        node
     } else {
-        return Err(anyhow!("no node found for {pubkey}"));
-    };
+        log::debug!("gossip_data: no node announcement found for {pubkey}");
+    }
+    let addresses = list_node.and_then(|n| n.addresses.as_ref());
+    let features = list_node.and_then(|n| n.features.clone());
     let list_channels = list_channels_task.await??.channels;
+    let getinfo = getinfo_task.await??;
+    let blockheight = getinfo.blockheight;
+    let our_peers = our_channels_task
+        .await??
+        .channels
+        .into_iter()
+        .filter(|c| is_active_or_opening(&c.state))
+        .map(|c| c.peer_id)
+        .collect::<HashSet<_>>();
+    let node_capacity_sat = list_channels
+        .iter()
+        .map(|c| c.amount_msat.msat() / 1000)
+        .sum();
+    let (hops_from_us, rank, impersonated_node) = {
+        let graph_cache = graph_cache.lock();
+        let hops_from_us = if our_peers.contains(&pubkey) {
+            1
+        } else {
+            hop_distance(&graph_cache.neighbors, getinfo.id, pubkey)
+        };
+        let impersonated_node = list_node.and_then(|n| n.alias.as_ref()).and_then(|alias| {
+            find_impersonated_node(
+                pubkey,
+                alias,
+                node_capacity_sat,
+                &alias_cache.lock(),
+                &graph_cache.capacities,
+            )
+        });
+        (
+            hops_from_us,
+            graph_cache.ranks.get(&pubkey).cloned(),
+            impersonated_node,
+        )
+    };
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
 
     let peerinfo = PeerInfo {
         pubkey,
         channel_count: Some(list_channels.len() as u64),
-        node_capacity_sat: Some(
-            list_channels
-                .iter()
-                .map(|c| c.amount_msat.msat() / 1000)
-                .sum(),
-        ),
-        has_clearnet: Some(list_node.addresses.as_ref().is_some_and(|a| {
+        node_capacity_sat: Some(node_capacity_sat),
+        node_known: Some(list_node.is_some()),
+        has_clearnet: Some(addresses.is_some_and(|a| {
             a.iter().any(|t| {
                 t.item_type == ListnodesNodesAddressesType::DNS
                     || t.item_type == ListnodesNodesAddressesType::IPV4
                     || t.item_type == ListnodesNodesAddressesType::IPV6
             })
         })),
-        has_tor: Some(list_node.addresses.as_ref().is_some_and(|a| {
+        has_tor: Some(addresses.is_some_and(|a| {
             a.iter().any(|t| {
                 t.item_type == ListnodesNodesAddressesType::TORV2
                     || t.item_type == ListnodesNodesAddressesType::TORV3
             })
         })),
-        anchor_support: if let Some(features) = &list_node.features {
+        anchor_support: if let Some(features) = &features {
             Some(check_feature(features, vec![22, 23])?)
         } else {
             Some(false)
         },
+        node_age_blocks: Some(
+            list_channels
+                .iter()
+                .map(|c| c.short_channel_id.block())
+                .min()
+                .map_or(0, |b| u64::from(blockheight.saturating_sub(b))),
+        ),
+        last_update_age_s: Some(
+            list_node
+                .and_then(|n| n.last_timestamp)
+                .map_or(u64::MAX, |t| unix_now_s.saturating_sub(u64::from(t))),
+        ),
+        median_fee_ppm: Some(median(
+            list_channels
+                .iter()
+                .map(|c| u64::from(c.fee_per_millionth))
+                .collect(),
+        )),
+        max_fee_ppm: Some(
+            list_channels
+                .iter()
+                .map(|c| u64::from(c.fee_per_millionth))
+                .max()
+                .unwrap_or(0),
+        ),
+        median_base_fee_msat: Some(median(
+            list_channels
+                .iter()
+                .map(|c| u64::from(c.base_fee_millisatoshi))
+                .collect(),
+        )),
+        zero_base_fee_ratio: Some(percentage(
+            list_channels
+                .iter()
+                .filter(|c| c.base_fee_millisatoshi == 0)
+                .count(),
+            list_channels.len(),
+        )),
+        median_channel_sat: Some(median(
+            list_channels
+                .iter()
+                .map(|c| c.amount_msat.msat() / 1000)
+                .collect(),
+        )),
+        min_channel_sat: Some(
+            list_channels
+                .iter()
+                .map(|c| c.amount_msat.msat() / 1000)
+                .min()
+                .unwrap_or(0),
+        ),
+        max_channel_sat: Some(
+            list_channels
+                .iter()
+                .map(|c| c.amount_msat.msat() / 1000)
+                .max()
+                .unwrap_or(0),
+        ),
+        active_channel_ratio: Some(percentage(
+            list_channels.iter().filter(|c| c.active).count(),
+            list_channels.len(),
+        )),
+        hops_from_us: Some(hops_from_us),
+        common_peers: Some(
+            list_channels
+                .iter()
+                .map(|c| c.destination)
+                .filter(|d| our_peers.contains(d))
+                .collect::<HashSet<_>>()
+                .len() as u64,
+        ),
+        capacity_percentile: Some(rank.as_ref().map_or(0, |r| r.capacity_percentile)),
+        channels_percentile: Some(rank.as_ref().map_or(0, |r| r.channels_percentile)),
+        centrality_rank: Some(rank.map_or(u64::MAX, |r| r.centrality_rank)),
+        impersonated_node,
+        implementation: Some(if let Some(features) = &features {
+            guess_implementation(features)?.to_string()
+        } else {
+            "unknown".to_string()
+        }),
+        features,
+        addresses: Some(
+            addresses
+                .into_iter()
+                .flatten()
+                .filter(|a| {
+                    a.item_type == ListnodesNodesAddressesType::IPV4
+                        || a.item_type == ListnodesNodesAddressesType::IPV6
+                })
+                .filter_map(|a| a.address.as_ref()?.parse::<IpAddr>().ok())
+                .collect(),
+        ),
     };
     log::debug!("gossip_data: done");
     Ok(peerinfo)
 }
 
+pub fn is_active_or_opening(state: &ChannelState) -> bool {
+    matches!(
+        state,
+        ChannelState::CHANNELD_NORMAL
+            | ChannelState::CHANNELD_AWAITING_LOCKIN
+            | ChannelState::CHANNELD_AWAITING_SPLICE
+            | ChannelState::DUALOPEND_AWAITING_LOCKIN
+            | ChannelState::DUALOPEND_OPEN_COMMITTED
+            | ChannelState::DUALOPEND_OPEN_COMMIT_READY
+            | ChannelState::DUALOPEND_OPEN_INIT
+            | ChannelState::OPENINGD
+    )
+}
+
+// Number of our active or opening channels with each peer
+pub async fn channels_with_us(rpc_path: &PathBuf) -> Result<HashMap<PublicKey, u64>, Error> {
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels;
+    let mut channel_counts = HashMap::new();
+    for chan in channels {
+        if is_active_or_opening(&chan.state) {
+            *channel_counts.entry(chan.peer_id).or_insert(0) += 1;
+        }
+    }
+    Ok(channel_counts)
+}
+
+async fn get_cluster_data(
+    rpc_path: &PathBuf,
+    pubkey: PublicKey,
+    graph_cache: Arc<Mutex<GraphCache>>,
+) -> Result<ClusterInfo, Error> {
+    let members = {
+        let graph_cache = graph_cache.lock();
+        match graph_cache.node_clusters.get(&pubkey) {
+            Some(i) => graph_cache.clusters[*i].members.clone(),
+            None => vec![pubkey],
+        }
+    };
+    let channel_counts = channels_with_us(rpc_path).await?;
+    Ok(ClusterInfo {
+        size: members.len() as u64,
+        channels_with_us: members.iter().filter_map(|m| channel_counts.get(m)).sum(),
+    })
+}
+
 async fn get_peer_data(
     rpc_path: &PathBuf,
     pubkey: PublicKey,
     their_funding_msat: Amount,
     channel_flags: ChannelFlags,
+    channel_params: ChannelParams,
 ) -> Result<OpeningInfo, Error> {
     let mut list_peers_rpc = ClnRpc::new(rpc_path).await?;
 
@@ -259,15 +621,7 @@ async fn get_peer_data(
 
     let mut multi_channel_count = 1;
     for peer in list_peers {
-        if peer.state == ChannelState::CHANNELD_NORMAL
-            || peer.state == ChannelState::CHANNELD_AWAITING_LOCKIN
-            || peer.state == ChannelState::CHANNELD_AWAITING_SPLICE
-            || peer.state == ChannelState::DUALOPEND_AWAITING_LOCKIN
-            || peer.state == ChannelState::DUALOPEND_OPEN_COMMITTED
-            || peer.state == ChannelState::DUALOPEND_OPEN_COMMIT_READY
-            || peer.state == ChannelState::DUALOPEND_OPEN_INIT
-            || peer.state == ChannelState::OPENINGD
-        {
+        if is_active_or_opening(&peer.state) {
             multi_channel_count += 1;
         }
     }
@@ -281,14 +635,290 @@ async fn get_peer_data(
         their_funding_sat: their_funding_msat.msat() / 1000,
         multi_channel_count,
         channel_flags,
+        channel_params,
+    })
+}
+
+async fn get_connection_data(
+    rpc_path: &PathBuf,
+    pubkey: PublicKey,
+) -> Result<ConnectionInfo, Error> {
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let peers = rpc
+        .call_typed(&ListpeersRequest {
+            id: Some(pubkey),
+            level: None,
+        })
+        .await?
+        .peers;
+
+    let Some(netaddr) = peers
+        .into_iter()
+        .filter(|p| p.connected)
+        .find_map(|p| p.netaddr?.into_iter().next())
+    else {
+        return Ok(ConnectionInfo::default());
+    };
+    if netaddr.contains(".onion") {
+        return Ok(ConnectionInfo {
+            is_tor: true,
+            ..Default::default()
+        });
+    }
+    let Some(ip) = parse_netaddr(&netaddr) else {
+        log::debug!("connection_data: could not parse netaddr {netaddr}");
+        return Ok(ConnectionInfo::default());
+    };
+    Ok(ConnectionInfo {
+        ip: Some(ip),
+        is_tor: false,
+        ip_version: if ip.is_ipv4() { 4 } else { 6 },
+        is_private_ip: is_private_ip(&ip),
+    })
+}
+
+fn parse_netaddr(netaddr: &str) -> Option<IpAddr> {
+    match SocketAddr::from_str(netaddr) {
+        Ok(s) => Some(s.ip()),
+        Err(_) => IpAddr::from_str(netaddr).ok(),
+    }
+    .map(|ip| ip.to_canonical())
+}
+
+pub fn node_ips(node: &ListnodesNodes) -> Vec<IpAddr> {
+    node.addresses
+        .iter()
+        .flatten()
+        .filter(|a| {
+            a.item_type == ListnodesNodesAddressesType::IPV4
+                || a.item_type == ListnodesNodesAddressesType::IPV6
+        })
+        .filter_map(|a| a.address.as_ref().and_then(|ip| parse_netaddr(ip)))
+        .collect()
+}
+
+async fn announced_ips(rpc: &mut ClnRpc, pubkey: PublicKey) -> Result<Vec<IpAddr>, Error> {
+    let nodes = rpc
+        .call_typed(&ListnodesRequest { id: Some(pubkey) })
+        .await?
+        .nodes;
+    Ok(nodes.iter().flat_map(node_ips).collect())
+}
+
+async fn get_geo_data(
+    rpc_path: &PathBuf,
+    pubkey: PublicKey,
+    geo_db: Arc<Mutex<GeoDb>>,
+    graph_cache: Arc<Mutex<GraphCache>>,
+    with_channel_count: bool,
+) -> Result<GeoInfo, Error> {
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let connected_ips: HashMap<PublicKey, IpAddr> = rpc
+        .call_typed(&ListpeersRequest {
+            id: None,
+            level: None,
+        })
+        .await?
+        .peers
+        .into_iter()
+        .filter(|p| p.connected)
+        .filter_map(|p| {
+            let ip = p.netaddr?.iter().find_map(|a| parse_netaddr(a))?;
+            Some((p.id, ip))
+        })
+        .collect();
+
+    let mut geo = GeoInfo::default();
+    let mut ips: Vec<IpAddr> = connected_ips.get(&pubkey).copied().into_iter().collect();
+    ips.extend(announced_ips(&mut rpc, pubkey).await?);
+    if let Some(range) = ips
+        .iter()
+        .find_map(|ip| geo_db.lock().lookup(ip).filter(|r| r.asn != 0).cloned())
+    {
+        geo.asn = range.asn;
+        geo.country = range.country;
+    }
+    if geo.asn == 0 || !with_channel_count {
+        return Ok(geo);
+    }
+
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels;
+    let mut channel_peers: HashMap<PublicKey, u64> = HashMap::new();
+    for chan in channels {
+        if chan.state == ChannelState::CHANNELD_NORMAL
+            || chan.state == ChannelState::CHANNELD_AWAITING_SPLICE
+        {
+            *channel_peers.entry(chan.peer_id).or_insert(0) += 1;
+        }
+    }
+    for (peer, count) in channel_peers {
+        let mut peer_ips: Vec<IpAddr> = connected_ips.get(&peer).copied().into_iter().collect();
+        if peer_ips.is_empty() {
+            // announced addresses from the gossip snapshot, a listnodes per peer is too slow
+            peer_ips = graph_cache
+                .lock()
+                .node_ips
+                .get(&peer)
+                .cloned()
+                .unwrap_or_default();
+        }
+        let peer_asn = peer_ips
+            .iter()
+            .find_map(|ip| geo_db.lock().lookup(ip).map(|r| r.asn));
+        if peer_asn == Some(geo.asn) {
+            geo.asn_channel_count += count;
+        }
+    }
+    Ok(geo)
+}
+
+// Per address, probes run in parallel
+const REACHABILITY_TIMEOUT_MS: u64 = 3000;
+// Nodes can announce many addresses, only the first ones are probed
+const REACHABILITY_MAX_ADDRESSES: usize = 4;
+
+async fn get_reachability_data(
+    rpc_path: &PathBuf,
+    pubkey: PublicKey,
+) -> Result<ReachabilityInfo, Error> {
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let addresses: Vec<(String, u16)> = rpc
+        .call_typed(&ListnodesRequest { id: Some(pubkey) })
+        .await?
+        .nodes
+        .into_iter()
+        .flat_map(|n| n.addresses.unwrap_or_default())
+        .filter(|a| {
+            a.item_type == ListnodesNodesAddressesType::DNS
+                || a.item_type == ListnodesNodesAddressesType::IPV4
+                || a.item_type == ListnodesNodesAddressesType::IPV6
+        })
+        .filter_map(|a| a.address.map(|host| (host, a.port)))
+        .take(REACHABILITY_MAX_ADDRESSES)
+        .collect();
+
+    let mut probes = JoinSet::new();
+    for (host, port) in addresses {
+        probes.spawn(async move {
+            let reachable = matches!(
+                timeout(
+                    Duration::from_millis(REACHABILITY_TIMEOUT_MS),
+                    probe_address(&host, port),
+                )
+                .await,
+                Ok(Ok(true))
+            );
+            log::debug!("reachability probe {host}:{port}: {reachable}");
+            reachable
+        });
+    }
+
+    let mut address_count = 0;
+    while let Some(reachable) = probes.join_next().await {
+        if reachable? {
+            address_count += 1;
+        }
+    }
+    Ok(ReachabilityInfo {
+        reachable: address_count > 0,
+        address_count,
     })
 }
 
+// Peers must not make us connect into our own network, so private addresses
+// are never probed, also not if a DNS name resolves to one
+async fn probe_address(host: &str, port: u16) -> Result<bool, Error> {
+    let Some(addr) = lookup_host((host, port))
+        .await?
+        .find(|a| !is_private_ip(&a.ip().to_canonical()) && !a.ip().is_multicast())
+    else {
+        log::debug!("reachability probe {host}:{port}: no public address");
+        return Ok(false);
+    };
+    Ok(TcpStream::connect(addr).await.is_ok())
+}
+
+pub fn is_private_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                // shared address space 100.64.0.0/10
+                || (v4.octets()[0] == 100 && (v4.octets()[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(v6) => {
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || v6.is_unique_local()
+                || v6.is_unicast_link_local()
+        }
+    }
+}
+
+async fn get_own_node_data(rpc_path: &PathBuf) -> Result<OwnNode, Error> {
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels;
+    let outputs = rpc
+        .call_typed(&ListfundsRequest { spent: None })
+        .await?
+        .outputs;
+
+    let mut own_node = OwnNode::default();
+    let mut total_msat = 0;
+    let mut inbound_msat = 0;
+    for chan in channels {
+        match chan.state {
+            ChannelState::CHANNELD_NORMAL | ChannelState::CHANNELD_AWAITING_SPLICE => {
+                own_node.channel_count += 1;
+                let chan_total_msat = chan.total_msat.map_or(0, |t| t.msat());
+                total_msat += chan_total_msat;
+                inbound_msat +=
+                    chan_total_msat.saturating_sub(chan.to_us_msat.map_or(0, |t| t.msat()));
+            }
+            ChannelState::CHANNELD_AWAITING_LOCKIN
+            | ChannelState::DUALOPEND_AWAITING_LOCKIN
+            | ChannelState::DUALOPEND_OPEN_COMMITTED
+            | ChannelState::DUALOPEND_OPEN_COMMIT_READY
+            | ChannelState::DUALOPEND_OPEN_INIT
+            | ChannelState::OPENINGD => own_node.pending_opens += 1,
+            _ => (),
+        }
+    }
+    own_node.total_capacity_sat = total_msat / 1000;
+    own_node.inbound_ratio = (inbound_msat * 100).checked_div(total_msat).unwrap_or(0);
+    own_node.onchain_sat = outputs
+        .iter()
+        .filter(|o| o.status == ListfundsOutputsStatus::CONFIRMED && !o.reserved)
+        .map(|o| o.amount_msat.msat() / 1000)
+        .sum();
+
+    log::debug!("own_node_data: {own_node:?}");
+    Ok(own_node)
+}
+
 pub async fn collect_data(
     plugin: &Plugin<PluginState>,
     pubkey: PublicKey,
     their_funding_msat: Amount,
     channel_flags: ChannelFlags,
+    channel_params: ChannelParams,
     custom_rule: &str,
     ping_length: u16,
 ) -> Result<PeerData, Error> {
@@ -301,74 +931,218 @@ pub async fn collect_data(
     let rpc_path =
         Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
 
-    let peerinfo = PeerInfo {
-        pubkey,
-        channel_count: None,
-        node_capacity_sat: None,
-        has_clearnet: None,
-        has_tor: None,
-        anchor_support: None,
-    };
-
-    let openinginfo = if custom_rule
-        .to_ascii_lowercase()
-        .contains("cln_multi_channel_count")
-    {
-        get_peer_data(&rpc_path, pubkey, their_funding_msat, channel_flags).await?
+    let names = rule_names(custom_rule)?;
+    let openinginfo = if names.uses("cln_multi_channel_count") {
+        get_peer_data(
+            &rpc_path,
+            pubkey,
+            their_funding_msat,
+            channel_flags,
+            channel_params,
+        )
+        .await?
     } else {
         OpeningInfo {
             their_funding_sat: their_funding_msat.msat() / 1000,
             multi_channel_count: 1,
             channel_flags,
+            channel_params,
         }
     };
 
-    let mut peer_data = PeerData {
-        ping: None,
-        peerinfo,
-        openinginfo,
-        oneml_data: None,
-        amboss_data: None,
-    };
-
-    let mut cache_hit = false;
+    let mut peer_data = PeerData::new(pubkey, openinginfo);
 
-    {
-        if let Some(cache) = plugin.state().peerdata_cache.lock().get(&pubkey) {
-            if unix_now_s - cache.age <= 3600 {
-                log::debug!("collect_data: cache hit");
-                cache_hit = true;
-                peer_data.ping = cache.peer_data.ping;
-                peer_data.peerinfo = cache.peer_data.peerinfo;
-                peer_data.oneml_data = cache.peer_data.oneml_data;
-                peer_data
-                    .amboss_data
-                    .clone_from(&cache.peer_data.amboss_data);
+    let config = plugin.state().config.lock().clone();
+    let cache = plugin
+        .state()
+        .peerdata_cache
+        .lock()
+        .touch(&pubkey, unix_now_s);
+    let mut fetch = HashSet::new();
+    for source in DataSource::ALL {
+        if !source.is_used_by(&names) {
+            continue;
+        }
+        match cache
+            .as_ref()
+            .and_then(|c| c.lookup(source, &config, unix_now_s, &peer_data.openinginfo))
+        {
+            Some(Ok(())) => {
+                log::debug!("collect_data: {source} cache hit");
+                source.copy(&cache.as_ref().unwrap().peer_data, &mut peer_data);
+            }
+            Some(Err(e)) if source == DataSource::Ext => {
+                log::debug!("collect_data: ext cached error, using unknown values: {e}");
+            }
+            Some(Err(e)) => {
+                return Err(anyhow!("Could not get {source} data (cached error): {e}"));
+            }
+            None => {
+                fetch.insert(source);
             }
         }
     }
 
+    log::debug!("collect_data: custom_rule: {custom_rule}");
+    refresh_rule_caches(plugin, &names).await?;
+
+    if names.uses_prefix("conn_") {
+        peer_data.connection = Some(get_connection_data(&rpc_path, pubkey).await?);
+    }
+    log::debug!("collect_data: connection: {:#?}", peer_data.connection);
+
+    if names.uses_prefix("geo_") {
+        if plugin.state().geo_db.lock().ranges.is_empty() {
+            return Err(anyhow!(
+                "Rule uses geo_ variables but {GEO_DB_FILE} is missing or empty"
+            ));
+        }
+        peer_data.geo = Some(
+            get_geo_data(
+                &rpc_path,
+                pubkey,
+                plugin.state().geo_db.clone(),
+                plugin.state().graph_cache.clone(),
+                names.uses("geo_asn_channel_count"),
+            )
+            .await?,
+        );
+    }
+    log::debug!("collect_data: geo: {:#?}", peer_data.geo);
+
+    if names.uses("conn_in_cidr") || names.uses("addr_in_cidr") {
+        let plugin_dir = Path::new(&plugin.configuration().lightning_dir).join(PLUGIN_NAME);
+        for file_name in cidr_files(custom_rule)? {
+            let cidrs = read_cidr_file(&plugin_dir.join(&file_name)).await?;
+            peer_data.cidr_lists.insert(file_name, cidrs);
+        }
+    }
+
+    if names.uses_prefix("self_") {
+        peer_data.own_node = Some(get_own_node_data(&rpc_path).await?);
+    }
+
+    if names.uses_prefix("hist_") {
+        if plugin.state().history_cache.lock().age == 0 {
+            refresh_history_cache(plugin.clone()).await?;
+        }
+        let mut history = plugin
+            .state()
+            .history_cache
+            .lock()
+            .peers
+            .get(&pubkey)
+            .cloned()
+            .unwrap_or_default();
+        if let Some(uptime) = plugin.state().uptime_cache.lock().get(&pubkey) {
+            apply_uptime_history(&mut history, uptime);
+        }
+        peer_data.history = Some(history);
+    }
+    log::debug!("collect_data: history: {:#?}", peer_data.history);
+
+    if names.uses_prefix("cluster_") {
+        peer_data.cluster =
+            Some(get_cluster_data(&rpc_path, pubkey, plugin.state().graph_cache.clone()).await?);
+    }
+    log::debug!("collect_data: cluster: {:#?}", peer_data.cluster);
+
+    let fetched =
+        fetch_sources(plugin, pubkey, &fetch, &mut peer_data, &config, ping_length).await?;
+
+    plugin.state().peerdata_cache.lock().store(
+        pubkey,
+        &peer_data,
+        &fetched,
+        unix_now_s,
+        config.cache_size,
+    );
+
+    // failed ext commands only make the ext_ variables unknown
+    if let Some((source, Some(e))) = fetched
+        .iter()
+        .find(|(s, e)| e.is_some() && *s != DataSource::Ext)
+    {
+        return Err(anyhow!("Could not get {source} data: {e}"));
+    }
+    log::debug!("collect_data: done");
+    Ok(peer_data)
+}
+
+/// Fills the caches that are needed to calculate some variables of the rule
+async fn refresh_rule_caches(plugin: &Plugin<PluginState>, names: &RuleNames) -> Result<(), Error> {
+    if (names.uses("cln_hops_from_us")
+        || names.uses("cln_capacity_percentile")
+        || names.uses("cln_channels_percentile")
+        || names.uses("cln_centrality_rank")
+        || names.uses("cln_alias_impersonation")
+        || names.uses_prefix("cluster_")
+        || names.uses("geo_asn_channel_count"))
+        && plugin.state().graph_cache.lock().age == 0
+    {
+        refresh_graph_cache(plugin.clone()).await?;
+    }
+    if names.uses("cln_alias_impersonation") && plugin.state().alias_cache.lock().is_empty() {
+        refresh_alias_cache(plugin.clone()).await?;
+    }
+    Ok(())
+}
+
+/// Queries the data sources in `fetch` in parallel, returns the fetched sources
+/// and their errors
+async fn fetch_sources(
+    plugin: &Plugin<PluginState>,
+    pubkey: PublicKey,
+    fetch: &HashSet<DataSource>,
+    peer_data: &mut PeerData,
+    config: &Config,
+    ping_length: u16,
+) -> Result<Vec<(DataSource, Option<String>)>, Error> {
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
     let network = plugin.configuration().network;
 
-    log::debug!("collect_data: custom_rule: {custom_rule}");
-    let ping_task = if !cache_hit && custom_rule.to_ascii_lowercase().contains("ping") {
+    let ping_task = if fetch.contains(&DataSource::Ping) {
         let plugin_ping = plugin.clone();
+        let (ping_count, ping_timeout_ms) = {
+            config.check_capability(Capability::Ping)?;
+            (config.ping_count, config.ping_timeout_ms)
+        };
         Some(tokio::spawn(async move {
-            ln_ping(plugin_ping, pubkey, 3, ping_length).await
+            ln_ping(
+                plugin_ping,
+                pubkey,
+                ping_count,
+                ping_length,
+                ping_timeout_ms,
+            )
+            .await
         }))
     } else {
         None
     };
 
-    let gossip_task = if !cache_hit && custom_rule.to_ascii_lowercase().contains("cln_") {
+    let graph_cache = plugin.state().graph_cache.clone();
+    let alias_cache = plugin.state().alias_cache.clone();
+    let gossip_task = if fetch.contains(&DataSource::Gossip) {
         Some(tokio::spawn(async move {
-            get_gossip_data(rpc_path, pubkey).await
+            get_gossip_data(rpc_path, pubkey, graph_cache, alias_cache).await
         }))
     } else {
         None
     };
 
-    let amboss_task = if !cache_hit && custom_rule.to_ascii_lowercase().contains("amboss_") {
+    let reachability_task = if fetch.contains(&DataSource::Reachability) {
+        let rpc_path =
+            Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+        Some(tokio::spawn(async move {
+            get_reachability_data(&rpc_path, pubkey).await
+        }))
+    } else {
+        None
+    };
+
+    let amboss_task = if fetch.contains(&DataSource::Amboss) {
         let network_amboss = network.clone();
         let amboss_lock = plugin.state().amboss_lock.clone();
         Some(tokio::spawn(async move {
@@ -387,7 +1161,7 @@ pub async fn collect_data(
         None
     };
 
-    let oneml_task = if !cache_hit && custom_rule.to_ascii_lowercase().contains("oneml_") {
+    let oneml_task = if fetch.contains(&DataSource::Oneml) {
         let oneml_lock = plugin.state().oneml_lock.clone();
         Some(tokio::spawn(async move {
             let mut attempts = 1;
@@ -404,42 +1178,406 @@ pub async fn collect_data(
         None
     };
 
+    let mempool_task = if fetch.contains(&DataSource::Mempool) {
+        let mempool_url = config.mempool_url.clone();
+        if mempool_url.is_empty() {
+            return Err(anyhow!(
+                "Rule uses mempool_ variables but {OPT_MEMPOOL_URL} is not set"
+            ));
+        }
+        let mempool_lock = plugin.state().mempool_lock.clone();
+        Some(tokio::spawn(async move {
+            let mut attempts = 1;
+            loop {
+                let result =
+                    get_mempool_data(pubkey, mempool_url.clone(), mempool_lock.clone()).await;
+                if result.is_ok() || attempts >= 3 {
+                    break result;
+                }
+                time::sleep(Duration::from_secs(attempts * 2)).await;
+                attempts += 1;
+            }
+        }))
+    } else {
+        None
+    };
+
+    let ext_task = if fetch.contains(&DataSource::Ext) {
+        let (ext_command, ext_timeout_s) = (config.ext_command.clone(), config.ext_timeout_s);
+        if ext_command.is_empty() {
+            return Err(anyhow!(
+                "Rule uses ext_ variables but {OPT_EXT_COMMAND} is not set"
+            ));
+        }
+        let input = json!({"pubkey":pubkey, "openinginfo":peer_data.openinginfo});
+        let ext_semaphore = plugin.state().ext_semaphore.clone();
+        Some(tokio::spawn(async move {
+            timeout(
+                Duration::from_secs(ext_timeout_s),
+                get_ext_data(ext_command, input, ext_semaphore),
+            )
+            .await
+        }))
+    } else {
+        None
+    };
+
+    let mut fetched = Vec::new();
+
     if let Some(p) = ping_task {
-        let pings = p.await??;
-        peer_data.ping = Some(u16::try_from(
-            pings.iter().map(|y| *y as usize).sum::<usize>() / pings.len(),
-        )?);
+        if let Some(ping_stats) = fetch_result(DataSource::Ping, p.await?, &mut fetched) {
+            peer_data.ping = Some(u16::try_from(ping_stats.average)?);
+            peer_data.ping_stats = Some(ping_stats);
+        }
     }
-    log::debug!("collect_data: ping: {:#?}", peer_data.ping);
+    log::debug!("collect_data: ping: {:#?}", peer_data.ping_stats);
 
     if let Some(gdata) = gossip_task {
-        peer_data.peerinfo = gdata.await??;
+        if let Some(peerinfo) = fetch_result(DataSource::Gossip, gdata.await?, &mut fetched) {
+            peer_data.peerinfo = peerinfo;
+        }
     }
     log::debug!("collect_data: peerinfo: {:#?}", peer_data.peerinfo);
 
+    if let Some(re) = reachability_task {
+        peer_data.reachability = fetch_result(DataSource::Reachability, re.await?, &mut fetched);
+    }
+    log::debug!("collect_data: reachability: {:#?}", peer_data.reachability);
+
     if let Some(ad) = amboss_task {
-        peer_data.amboss_data = Some(ad.await??.data);
+        peer_data.amboss_data =
+            fetch_result(DataSource::Amboss, ad.await?, &mut fetched).map(|a| a.data);
     }
     log::debug!("collect_data: amboss_data: {:#?}", peer_data.amboss_data);
 
     if let Some(ml) = oneml_task {
-        peer_data.oneml_data = Some(ml.await??);
+        peer_data.oneml_data = fetch_result(DataSource::Oneml, ml.await?, &mut fetched);
     }
     log::debug!("collect_data: oneml_data: {:#?}", peer_data.oneml_data);
 
-    let mut cache = plugin.state().peerdata_cache.lock();
-    cache.insert(
+    if let Some(mp) = mempool_task {
+        peer_data.mempool_data = fetch_result(DataSource::Mempool, mp.await?, &mut fetched);
+    }
+    log::debug!("collect_data: mempool_data: {:#?}", peer_data.mempool_data);
+
+    if let Some(ext) = ext_task {
+        let result = ext
+            .await?
+            .unwrap_or_else(|_| Err(anyhow!("ext command timed out")));
+        if let Err(e) = &result {
+            log::warn!("ext command failed, using unknown values: {e}");
+        }
+        peer_data.ext_data = fetch_result(DataSource::Ext, result, &mut fetched);
+    }
+    log::debug!("collect_data: ext_data: {:#?}", peer_data.ext_data);
+
+    Ok(fetched)
+}
+
+// Minimum time between two prefetches, so they don't slow down channel opens
+const PREFETCH_INTERVAL_MS: u128 = 2000;
+
+/// Fills the cache with the data sources the custom rule needs for a peer
+/// that just connected, so a channel open by that peer is answered quickly.
+/// Prefetches run one at a time.
+pub async fn prefetch_data(plugin: Plugin<PluginState>, pubkey: PublicKey) -> Result<(), Error> {
+    let config = plugin.state().config.lock().clone();
+    if !config.prefetch
+        || config.custom_rule.is_empty()
+        || plugin.state().pubkey_list.lock().contains(&pubkey)
+    {
+        return Ok(());
+    }
+    let Ok(_permit) = plugin
+        .state()
+        .prefetch_semaphore
+        .clone()
+        .try_acquire_owned()
+    else {
+        log::debug!("prefetch_data: too many prefetches waiting, skipping {pubkey}");
+        return Ok(());
+    };
+
+    // peers we already have channels with rarely open another one
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    if rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: Some(pubkey),
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels
+        .iter()
+        .any(|c| is_active_or_opening(&c.state))
+    {
+        return Ok(());
+    }
+
+    let mut last_prefetch = plugin.state().prefetch_lock.lock().await;
+    let mut unix_now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    while unix_now_ms.saturating_sub(*last_prefetch) <= PREFETCH_INTERVAL_MS {
+        time::sleep(Duration::from_millis(100)).await;
+        unix_now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+    }
+    let unix_now_s = u64::try_from(unix_now_ms / 1000)?;
+
+    let names = rule_names(&config.custom_rule)?;
+    let cache = plugin.state().peerdata_cache.lock().get(&pubkey).cloned();
+    let fetch: HashSet<DataSource> = DataSource::ALL
+        .into_iter()
+        // the ext command gets the details of the channel open and probing
+        // addresses of every connecting peer would be too easy to abuse
+        .filter(|s| *s != DataSource::Ext && *s != DataSource::Reachability && s.is_used_by(&names))
+        .filter(|s| {
+            cache
+                .as_ref()
+                .and_then(|c| c.lookup(*s, &config, unix_now_s, &OpeningInfo::default()))
+                .is_none()
+        })
+        .collect();
+    if fetch.is_empty() {
+        return Ok(());
+    }
+    log::debug!("prefetch_data: {pubkey} {fetch:?}");
+
+    refresh_rule_caches(&plugin, &names).await?;
+    let mut peer_data = PeerData::new(pubkey, OpeningInfo::default());
+    let fetched = fetch_sources(
+        &plugin,
         pubkey,
-        PeerDataCache {
-            peer_data: peer_data.clone(),
-            age: unix_now_s,
-        },
+        &fetch,
+        &mut peer_data,
+        &config,
+        config.ping_length,
+    )
+    .await?;
+    // don't let a transient error reject the channel open later on
+    let fetched: Vec<(DataSource, Option<String>)> = fetched
+        .into_iter()
+        .filter(|(source, error)| {
+            if let Some(e) = error {
+                log::debug!("prefetch_data: {pubkey} {source:?}: {e}");
+            }
+            error.is_none()
+        })
+        .collect();
+    plugin.state().peerdata_cache.lock().store(
+        pubkey,
+        &peer_data,
+        &fetched,
+        unix_now_s,
+        config.cache_size,
     );
-    log::debug!("collect_data: done");
-    Ok(peer_data)
+
+    *last_prefetch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    log::debug!("prefetch_data: {pubkey} done");
+    Ok(())
+}
+
+fn fetch_result<T>(
+    source: DataSource,
+    result: Result<T, Error>,
+    fetched: &mut Vec<(DataSource, Option<String>)>,
+) -> Option<T> {
+    match result {
+        Ok(o) => {
+            fetched.push((source, None));
+            Some(o)
+        }
+        Err(e) => {
+            fetched.push((source, Some(e.to_string())));
+            None
+        }
+    }
+}
+
+// An alias is only an impersonation if the other node is at least this
+// many times bigger than the peer (or than the minimum capacity)
+const IMPERSONATION_CAPACITY_FACTOR: u64 = 10;
+const IMPERSONATION_MIN_CAPACITY_SAT: u64 = 1_000_000;
+// Shorter aliases are too generic to compare
+const IMPERSONATION_MIN_ALIAS_LEN: usize = 3;
+
+fn find_impersonated_node(
+    pubkey: PublicKey,
+    alias: &str,
+    capacity_sat: u64,
+    alias_cache: &HashMap<PublicKey, String>,
+    capacities: &HashMap<PublicKey, u64>,
+) -> Option<PublicKey> {
+    let alias = normalize_alias(alias);
+    if alias.len() < IMPERSONATION_MIN_ALIAS_LEN {
+        return None;
+    }
+    let max_distance = match alias.len() {
+        0..=4 => 0,
+        5..=10 => 1,
+        _ => 2,
+    };
+    let min_victim_capacity_sat = capacity_sat
+        .max(IMPERSONATION_MIN_CAPACITY_SAT)
+        .saturating_mul(IMPERSONATION_CAPACITY_FACTOR);
+
+    alias_cache
+        .iter()
+        .filter(|(node, _)| **node != pubkey)
+        .filter_map(|(node, other_alias)| {
+            let other_capacity_sat = *capacities.get(node)?;
+            if other_capacity_sat < min_victim_capacity_sat {
+                return None;
+            }
+            let other_alias = normalize_alias(other_alias);
+            if other_alias.len().abs_diff(alias.len()) > max_distance
+                || edit_distance(&alias, &other_alias) > max_distance
+            {
+                return None;
+            }
+            Some((*node, other_capacity_sat))
+        })
+        .max_by_key(|(_, other_capacity_sat)| *other_capacity_sat)
+        .map(|(node, _)| node)
+}
+
+// Lowercase the alias, fold characters that look alike into one latin letter
+// and drop everything that is not a letter or digit
+fn normalize_alias(alias: &str) -> Vec<char> {
+    let folded = alias
+        .chars()
+        .flat_map(char::to_lowercase)
+        .filter_map(|c| match c {
+            '0' | 'о' | 'ο' | 'ó' | 'ò' | 'ô' | 'ö' | 'õ' | 'ø' => Some('o'),
+            '1' | 'i' | 'l' | 'ı' | 'і' | 'ι' | 'í' | 'ì' | 'î' | 'ï' | '|' | '!' => {
+                Some('l')
+            }
+            '3' | 'е' | 'ε' | 'é' | 'è' | 'ê' | 'ë' => Some('e'),
+            '4' | 'а' | 'α' | 'á' | 'à' | 'â' | 'ä' | 'ã' | 'å' | '@' => Some('a'),
+            '5' | 'ѕ' | '$' => Some('s'),
+            '7' | 'т' | 'τ' => Some('t'),
+            '8' | 'в' | 'β' => Some('b'),
+            'с' | 'ç' => Some('c'),
+            'р' | 'ρ' => Some('p'),
+            'х' | 'χ' => Some('x'),
+            'у' | 'ү' => Some('y'),
+            'к' | 'κ' => Some('k'),
+            'м' => Some('m'),
+            'н' => Some('h'),
+            'ν' => Some('v'),
+            'ú' | 'ù' | 'û' | 'ü' | 'υ' => Some('u'),
+            'ñ' => Some('n'),
+            c if c.is_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect::<String>();
+    folded
+        .replace("rn", "m")
+        .replace("vv", "w")
+        .chars()
+        .collect()
+}
+
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            current[j + 1] = (previous[j] + usize::from(ca != cb))
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn hop_distance(
+    neighbors: &HashMap<PublicKey, HashSet<PublicKey>>,
+    from: PublicKey,
+    to: PublicKey,
+) -> u64 {
+    if from == to {
+        return 0;
+    }
+    let mut visited = HashSet::from([from]);
+    let mut frontier = vec![from];
+    let mut hops = 0;
+    while !frontier.is_empty() {
+        hops += 1;
+        let mut next = Vec::new();
+        for node in frontier {
+            for neighbor in neighbors.get(&node).into_iter().flatten() {
+                if *neighbor == to {
+                    return hops;
+                }
+                if visited.insert(*neighbor) {
+                    next.push(*neighbor);
+                }
+            }
+        }
+        frontier = next;
+    }
+    u64::MAX
+}
+
+pub fn median(mut values: Vec<u64>) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) / 2
+    } else {
+        values[mid]
+    }
+}
+
+fn percentage(part: usize, total: usize) -> u64 {
+    if total == 0 {
+        return 0;
+    }
+    (part * 100 / total) as u64
 }
 
-fn check_feature(hex: &str, check_bits: Vec<u16>) -> Result<bool, Error> {
+/// Best effort guess of the lightning implementation based on feature bits
+/// that are (mostly) only set by one implementation. Implementations adopt
+/// each other's features over time, so the checks overlap and the first
+/// match wins, e.g. an lnd node with option_quiesce is still guessed as lnd.
+fn guess_implementation(features: &str) -> Result<&'static str, Error> {
+    // lnd: script_enforced_lease or amp
+    if check_feature(features, vec![2022, 2023])? || check_feature(features, vec![30, 31])? {
+        return Ok("lnd");
+    }
+    // eclair: trampoline_payment_prototype
+    if check_feature(features, vec![148, 149])? {
+        return Ok("eclair");
+    }
+    // cln: option_dual_fund, option_provide_storage or option_quiesce
+    if check_feature(features, vec![28, 29])?
+        || check_feature(features, vec![42, 43])?
+        || check_feature(features, vec![34, 35])?
+    {
+        return Ok("cln");
+    }
+    // ldk: option_onion_messages without any of the above
+    if check_feature(features, vec![38, 39])? {
+        return Ok("ldk");
+    }
+    Ok("unknown")
+}
+
+pub fn check_feature(hex: &str, check_bits: Vec<u16>) -> Result<bool, Error> {
     let mut bits = Vec::new();
     for hex_char in hex.chars() {
         let binary_string = match hex_char.to_digit(16) {
@@ -469,67 +1607,127 @@ fn check_feature(hex: &str, check_bits: Vec<u16>) -> Result<bool, Error> {
     Ok(result)
 }
 
+// All pings to a peer together, so one slow peer can't hold up the
+// openchannel hook for long
+const PING_SERIES_TIMEOUT_MS: u64 = 10_000;
+
 pub async fn ln_ping(
     plugin: Plugin<PluginState>,
     pubkey: PublicKey,
     count: u64,
     ping_length: u16,
-) -> Result<Vec<u16>, Error> {
-    let timeout_ms = 5000;
+    timeout_ms: u16,
+) -> Result<PingStats, Error> {
     let rpc_path =
         Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
     let mut rpc = ClnRpc::new(rpc_path).await?;
     let mut results = Vec::new();
-    let mut c = 0;
-    while c < count {
-        c += 1;
-        let now = Instant::now();
-        let timeout_result = match timeout(
-            Duration::from_millis(u64::from(timeout_ms)),
-            rpc.call_typed(&PingRequest {
-                len: Some(ping_length),
-                pongbytes: Some(ping_length),
-                id: pubkey,
-            }),
-        )
-        .await
-        {
-            Ok(o) => o,
-            Err(e) => {
-                results.push(timeout_ms);
-                notify(
-                    &plugin,
-                    "Clnrod ping TIMEOUT.",
-                    &format!(
-                        "Pinging {pubkey} {c}/{count} times with {ping_length} bytes TIMED OUT: {e}\
-                        \n Please check if the `lightning-cli ping` command is stuck for your \
-                        node and requires a restart of CLN"
-                    ),
-                    Some(pubkey),
-                    NotifyVerbosity::Error,
-                )
-                .await;
-                break;
-            }
-        };
-        let ping_response = match timeout_result {
-            Ok(o) => o,
-            Err(e) => {
-                results.push(timeout_ms);
-                log::warn!("Ping error: {e}");
-                time::sleep(Duration::from_millis(250)).await;
-                continue;
+    let pings = async {
+        let mut c = 0;
+        while c < count {
+            c += 1;
+            let now = Instant::now();
+            let timeout_result = match timeout(
+                Duration::from_millis(u64::from(timeout_ms)),
+                rpc.call_typed(&PingRequest {
+                    len: Some(ping_length),
+                    pongbytes: Some(ping_length),
+                    id: pubkey,
+                }),
+            )
+            .await
+            {
+                Ok(o) => o,
+                Err(e) => {
+                    results.push(None);
+                    notify(
+                        &plugin,
+                        "Clnrod ping TIMEOUT.",
+                        &format!(
+                            "Pinging {pubkey} {c}/{count} times with {ping_length} bytes TIMED OUT: {e}\
+                            \n Please check if the `lightning-cli ping` command is stuck for your \
+                            node and requires a restart of CLN"
+                        ),
+                        Some(pubkey),
+                        NotifyVerbosity::Error,
+                    )
+                    .await;
+                    break;
+                }
+            };
+            let ping_response = match timeout_result {
+                Ok(o) => o,
+                Err(e) => {
+                    results.push(None);
+                    log::warn!("Ping error: {e}");
+                    time::sleep(Duration::from_millis(250)).await;
+                    continue;
+                }
+            };
+            if ping_response.totlen < ping_length {
+                log::info!("Did not receive the full length ping back");
             }
-        };
-        if ping_response.totlen < ping_length {
-            log::info!("Did not receive the full length ping back");
+            let ping = u16::try_from(now.elapsed().as_millis())?;
+            log::info!("Pinged {pubkey} {c}/{count} times with {ping_length} bytes in {ping}ms");
+            results.push(Some(ping));
+            time::sleep(Duration::from_millis(250)).await;
+        }
+        Ok::<(), Error>(())
+    };
+    match timeout(Duration::from_millis(PING_SERIES_TIMEOUT_MS), pings).await {
+        Ok(o) => o?,
+        Err(_) => {
+            log::info!(
+                "Pinging {pubkey} took longer than {PING_SERIES_TIMEOUT_MS}ms, \
+                counting {} unanswered pings as lost",
+                count - results.len() as u64
+            );
+            results.resize(usize::try_from(count)?, None);
         }
-        let ping = u16::try_from(now.elapsed().as_millis())?;
-        log::info!("Pinged {pubkey} {c}/{count} times with {ping_length} bytes in {ping}ms");
-        results.push(ping);
-        time::sleep(Duration::from_millis(250)).await;
     }
 
-    results.sort_unstable();
-    Ok(results)
+    Ok(ping_stats(&results, timeout_ms))
+}
+
+// Statistics of the pings in the order they were sent, `None` for lost pings
+fn ping_stats(results: &[Option<u16>], timeout_ms: u16) -> PingStats {
+    let received = results
+        .iter()
+        .flatten()
+        .map(|p| u64::from(*p))
+        .collect::<Vec<_>>();
+    let sent = results.len() as u64;
+    // lost pings count as the timeout for the average to stay compatible with `ping`
+    let average = (received.iter().sum::<u64>()
+        + (sent - received.len() as u64) * u64::from(timeout_ms))
+    .checked_div(sent)
+    .unwrap_or(u64::from(timeout_ms));
+    if received.is_empty() {
+        return PingStats {
+            sent,
+            received: 0,
+            average,
+            min: u64::MAX,
+            median: u64::MAX,
+            max: u64::MAX,
+            jitter: u64::MAX,
+            loss_pct: 100,
+        };
+    }
+    let jitter = received
+        .windows(2)
+        .map(|w| w[0].abs_diff(w[1]))
+        .sum::<u64>()
+        .checked_div(received.len() as u64 - 1)
+        .unwrap_or(0);
+    PingStats {
+        sent,
+        received: received.len() as u64,
+        average,
+        min: *received.iter().min().unwrap(),
+        median: median(received.clone()),
+        max: *received.iter().max().unwrap(),
+        jitter,
+        loss_pct: (sent - received.len() as u64) * 100 / sent,
+    }
 }
@@ -1,8 +1,15 @@
-use std::{collections::HashSet, path::Path, str::FromStr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{Context, Error, anyhow};
 use cln_plugin::{ConfiguredPlugin, Plugin, options};
-use cln_rpc::{RpcError, primitives::PublicKey};
+use cln_rpc::{ClnRpc, RpcError, model::requests::GetinfoRequest, primitives::PublicKey};
 use parking_lot::Mutex;
 use serde_json::json;
 use tokio::{
@@ -12,28 +19,69 @@ use tokio::{
 
 use crate::{
     OPT_BLOCK_MODE,
+    OPT_CACHE_SIZE,
+    OPT_CACHE_TTL_AMBOSS,
+    OPT_CACHE_TTL_ERROR,
+    OPT_CACHE_TTL_EXT,
+    OPT_CACHE_TTL_GOSSIP,
+    OPT_CACHE_TTL_MEMPOOL,
+    OPT_CACHE_TTL_ONEML,
+    OPT_CACHE_TTL_PING,
+    OPT_CACHE_TTL_REACHABILITY,
     OPT_CUSTOM_RULE,
     OPT_DENY_MESSAGE,
     OPT_EMAIL_FROM,
     OPT_EMAIL_TO,
+    OPT_EXT_COMMAND,
+    OPT_EXT_TIMEOUT,
     OPT_LEAK_REASON,
+    OPT_MEMPOOL_URL,
     OPT_NOTIFY_VERBOSITY,
+    OPT_PING_COUNT,
     OPT_PING_LENGTH,
+    OPT_PING_TIMEOUT,
+    OPT_PREFETCH,
     OPT_SMTP_PASSWORD,
     OPT_SMTP_PORT,
     OPT_SMTP_SERVER,
     OPT_SMTP_USERNAME,
+    OPT_UNKNOWN_POLICY,
     PLUGIN_NAME,
     PluginState,
-    parser::parse_rule,
-    structs::{BlockMode, Config, NotifyVerbosity},
+    parser::{parse_rule, rule_names},
+    structs::{
+        BlockMode,
+        CacheFile,
+        Capability,
+        Cidr,
+        ClnVersion,
+        Config,
+        DataSource,
+        GeoDb,
+        GeoRange,
+        NotifyVerbosity,
+        PING_COUNT_MAX,
+        PeerUptime,
+        UnknownPolicy,
+        geo_ip_bits,
+    },
+    tasks::{CACHE_FILE, CACHE_FILE_VERSION, UPTIME_FILE},
 };
 
+pub const GEO_DB_FILE: &str = "ip2asn.tsv";
+
 pub async fn read_config(
     lightning_dir: String,
     plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
     state: &PluginState,
 ) -> Result<(), Error> {
+    match get_cln_version(plugin).await {
+        Ok(version) => {
+            log::info!("CLN version: {version}");
+            state.config.lock().cln_version = Some(version);
+        }
+        Err(e) => log::warn!("Could not read CLN version, not disabling any features: {e}"),
+    }
     get_startup_options(plugin, state)?;
 
     let plugin_dir = Path::new(&lightning_dir).join(PLUGIN_NAME);
@@ -48,6 +96,9 @@ pub async fn read_config(
 
     read_pubkey_list(state.pubkey_list.clone(), &plugin_dir, block_mode).await?;
     read_zeroconf_list(state.zero_conf_list.clone(), &plugin_dir).await?;
+    read_uptime_cache(state.uptime_cache.clone(), &plugin_dir).await?;
+    read_geo_db(state.geo_db.clone(), &plugin_dir).await?;
+    read_peerdata_cache(state, &plugin_dir).await?;
 
     let mut config = state.config.lock();
     activate_mail(&mut config);
@@ -55,6 +106,16 @@ pub async fn read_config(
     Ok(())
 }
 
+async fn get_cln_version(
+    plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
+) -> Result<ClnVersion, Error> {
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let mut rpc = ClnRpc::new(rpc_path).await?;
+    let getinfo = rpc.call_typed(&GetinfoRequest {}).await?;
+    ClnVersion::from_str(&getinfo.version)
+}
+
 pub async fn read_pubkey_list(
     pubkey_list: Arc<Mutex<HashSet<PublicKey>>>,
     plugin_dir: &Path,
@@ -121,6 +182,160 @@ pub async fn read_zeroconf_list(
     Ok((removed, added))
 }
 
+pub async fn read_uptime_cache(
+    uptime_cache: Arc<Mutex<HashMap<PublicKey, PeerUptime>>>,
+    plugin_dir: &Path,
+) -> Result<(), Error> {
+    let file_path = plugin_dir.join(UPTIME_FILE);
+    if !file_path.exists() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&file_path).await?;
+    match serde_json::from_str(&content) {
+        Ok(c) => {
+            *uptime_cache.lock() = c;
+            log::info!(
+                "Loaded uptime history of {} peers",
+                uptime_cache.lock().len()
+            );
+        }
+        Err(e) => {
+            log::warn!("Could not read {UPTIME_FILE}, starting with empty uptime history: {e}")
+        }
+    }
+    Ok(())
+}
+
+pub async fn read_peerdata_cache(state: &PluginState, plugin_dir: &Path) -> Result<(), Error> {
+    let file_path = plugin_dir.join(CACHE_FILE);
+    if !file_path.exists() {
+        return Ok(());
+    }
+
+    let content = fs::read_to_string(&file_path).await?;
+    let cache_file = match serde_json::from_str::<serde_json::Value>(&content) {
+        Ok(v)
+            if v.get("version").and_then(serde_json::Value::as_u64) != Some(CACHE_FILE_VERSION) =>
+        {
+            log::info!("{CACHE_FILE} has an old format, starting with empty cache");
+            return Ok(());
+        }
+        Ok(v) => serde_json::from_value::<CacheFile>(v),
+        Err(e) => Err(e),
+    };
+    match cache_file {
+        Ok(mut c) => {
+            let unix_now_s = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let config = state.config.lock().clone();
+            if c.mempool_url != config.mempool_url || c.ext_command != config.ext_command {
+                log::info!(
+                    "{CACHE_FILE} was saved with a different {OPT_MEMPOOL_URL} or \
+                    {OPT_EXT_COMMAND}, not loading cached data"
+                );
+            } else {
+                c.peerdata.prune(&config, unix_now_s);
+                log::info!("Loaded cached data of {} peers", c.peerdata.peer_count());
+                *state.peerdata_cache.lock() = c.peerdata;
+            }
+            // aliases come from gossip and are refreshed hourly
+            if unix_now_s.saturating_sub(c.saved_at) < config.cache_ttl_gossip_s {
+                *state.alias_cache.lock() = c.aliases;
+            }
+        }
+        Err(e) => {
+            log::warn!("Could not read {CACHE_FILE}, starting with empty cache: {e}")
+        }
+    }
+    Ok(())
+}
+
+pub async fn read_geo_db(geo_db: Arc<Mutex<GeoDb>>, plugin_dir: &Path) -> Result<usize, Error> {
+    let file_path = plugin_dir.join(GEO_DB_FILE);
+    if !file_path.exists() {
+        *geo_db.lock() = GeoDb::default();
+        return Ok(0);
+    }
+
+    let content = fs::read_to_string(&file_path)
+        .await
+        .context(format!("Could not read {}", file_path.display()))?;
+    let mut ranges = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        // a bad line in a third party dataset must not disable the plugin
+        match parse_geo_range(line) {
+            Ok(range) => ranges.push(range),
+            Err(e) => log::warn!("Skipping {GEO_DB_FILE}, line {}: {e}", i + 1),
+        }
+    }
+    ranges.sort_by_key(|r| r.start);
+
+    let count = ranges.len();
+    log::info!("Loaded {count} ip ranges from {GEO_DB_FILE}");
+    geo_db.lock().ranges = ranges;
+    Ok(count)
+}
+
+fn parse_geo_range(line: &str) -> Result<GeoRange, Error> {
+    let separator = if line.contains('\t') { '\t' } else { ',' };
+    let mut columns = line.split(separator).map(|c| c.trim().trim_matches('"'));
+    let mut next_column = |name: &str| {
+        columns
+            .next()
+            .ok_or_else(|| anyhow!("Missing column `{name}`"))
+    };
+
+    let start = IpAddr::from_str(next_column("range_start")?)?;
+    let end = IpAddr::from_str(next_column("range_end")?)?;
+    let asn = next_column("asn")?;
+    let asn = asn
+        .strip_prefix("AS")
+        .unwrap_or(asn)
+        .parse::<u64>()
+        .map_err(|e| anyhow!("Invalid asn `{asn}`: {e}"))?;
+    let country = next_column("country")?;
+    // ip2asn dumps use `None` for unrouted ranges
+    let country = if country.eq_ignore_ascii_case("none") {
+        String::new()
+    } else {
+        country.to_ascii_uppercase()
+    };
+
+    let (start, end) = (geo_ip_bits(&start), geo_ip_bits(&end));
+    if start > end {
+        return Err(anyhow!("range_start is after range_end"));
+    }
+    Ok(GeoRange {
+        start,
+        end,
+        asn,
+        country,
+    })
+}
+
+pub async fn read_cidr_file(file_path: &Path) -> Result<Vec<Cidr>, Error> {
+    let content = fs::read_to_string(file_path)
+        .await
+        .context(format!("Could not read CIDR file {}", file_path.display()))?;
+    let mut cidrs = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        cidrs.push(Cidr::from_str(line).context(format!(
+            "{}, line {}",
+            file_path.display(),
+            i + 1
+        ))?);
+    }
+    Ok(cidrs)
+}
+
 fn get_startup_options(
     plugin: &ConfiguredPlugin<PluginState, tokio::io::Stdin, tokio::io::Stdout>,
     state: &PluginState,
@@ -141,6 +356,12 @@ fn get_startup_options(
     if let Some(pl) = plugin.option_str(OPT_PING_LENGTH)? {
         check_option(&mut config, OPT_PING_LENGTH, &pl)?;
     }
+    if let Some(pc) = plugin.option_str(OPT_PING_COUNT)? {
+        check_option(&mut config, OPT_PING_COUNT, &pc)?;
+    }
+    if let Some(pt) = plugin.option_str(OPT_PING_TIMEOUT)? {
+        check_option(&mut config, OPT_PING_TIMEOUT, &pt)?;
+    }
     if let Some(smtp_user) = plugin.option_str(OPT_SMTP_USERNAME)? {
         check_option(&mut config, OPT_SMTP_USERNAME, &smtp_user)?;
     }
@@ -162,6 +383,48 @@ fn get_startup_options(
     if let Some(nv) = plugin.option_str(OPT_NOTIFY_VERBOSITY)? {
         check_option(&mut config, OPT_NOTIFY_VERBOSITY, &nv)?;
     }
+    if let Some(mu) = plugin.option_str(OPT_MEMPOOL_URL)? {
+        check_option(&mut config, OPT_MEMPOOL_URL, &mu)?;
+    }
+    if let Some(up) = plugin.option_str(OPT_UNKNOWN_POLICY)? {
+        check_option(&mut config, OPT_UNKNOWN_POLICY, &up)?;
+    }
+    if let Some(ec) = plugin.option_str(OPT_EXT_COMMAND)? {
+        check_option(&mut config, OPT_EXT_COMMAND, &ec)?;
+    }
+    if let Some(et) = plugin.option_str(OPT_EXT_TIMEOUT)? {
+        check_option(&mut config, OPT_EXT_TIMEOUT, &et)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_PING)? {
+        check_option(&mut config, OPT_CACHE_TTL_PING, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_GOSSIP)? {
+        check_option(&mut config, OPT_CACHE_TTL_GOSSIP, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_REACHABILITY)? {
+        check_option(&mut config, OPT_CACHE_TTL_REACHABILITY, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_AMBOSS)? {
+        check_option(&mut config, OPT_CACHE_TTL_AMBOSS, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_ONEML)? {
+        check_option(&mut config, OPT_CACHE_TTL_ONEML, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_MEMPOOL)? {
+        check_option(&mut config, OPT_CACHE_TTL_MEMPOOL, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_EXT)? {
+        check_option(&mut config, OPT_CACHE_TTL_EXT, &ct)?;
+    }
+    if let Some(ct) = plugin.option_str(OPT_CACHE_TTL_ERROR)? {
+        check_option(&mut config, OPT_CACHE_TTL_ERROR, &ct)?;
+    }
+    if let Some(cs) = plugin.option_str(OPT_CACHE_SIZE)? {
+        check_option(&mut config, OPT_CACHE_SIZE, &cs)?;
+    }
+    if let Some(pf) = plugin.option_str(OPT_PREFETCH)? {
+        check_option(&mut config, OPT_PREFETCH, &pf)?;
+    }
 
     log::info!("all options valid!");
 
@@ -188,8 +451,11 @@ fn check_option(config: &mut Config, name: &str, value: &options::Value) -> Resu
             }
         }
         n if n.eq(OPT_CUSTOM_RULE) => {
-            parse_rule(value.as_str().unwrap())?;
-            config.custom_rule = value.as_str().unwrap().to_string();
+            let custom_rule = value.as_str().unwrap();
+            if DataSource::Ping.is_used_by(&rule_names(custom_rule)?) {
+                config.check_capability(Capability::Ping)?;
+            }
+            config.custom_rule = custom_rule.to_string();
         }
         n if n.eq(OPT_PING_LENGTH) => {
             let ping_length = u16::try_from(value.as_i64().unwrap())
@@ -199,6 +465,27 @@ fn check_option(config: &mut Config, name: &str, value: &options::Value) -> Resu
             }
             config.ping_length = ping_length;
         }
+        n if n.eq(OPT_PING_COUNT) => {
+            let ping_count = u64::try_from(value.as_i64().unwrap())
+                .context(format!("{OPT_PING_COUNT} out of valid range"))?;
+            if ping_count == 0 {
+                return Err(anyhow!("{OPT_PING_COUNT} must be greater than 0"));
+            }
+            if ping_count > PING_COUNT_MAX {
+                return Err(anyhow!(
+                    "{OPT_PING_COUNT} must not be greater than {PING_COUNT_MAX}"
+                ));
+            }
+            config.ping_count = ping_count;
+        }
+        n if n.eq(OPT_PING_TIMEOUT) => {
+            let ping_timeout_ms = u16::try_from(value.as_i64().unwrap())
+                .context(format!("{OPT_PING_TIMEOUT} out of valid range"))?;
+            if ping_timeout_ms == 0 {
+                return Err(anyhow!("{OPT_PING_TIMEOUT} must be greater than 0"));
+            }
+            config.ping_timeout_ms = ping_timeout_ms;
+        }
         n if n.eq(OPT_SMTP_USERNAME) => config.smtp_username = value.as_str().unwrap().to_string(),
         n if n.eq(OPT_SMTP_PASSWORD) => config.smtp_password = value.as_str().unwrap().to_string(),
         n if n.eq(OPT_SMTP_SERVER) => config.smtp_server = value.as_str().unwrap().to_string(),
@@ -211,11 +498,61 @@ fn check_option(config: &mut Config, name: &str, value: &options::Value) -> Resu
         n if n.eq(OPT_NOTIFY_VERBOSITY) => {
             config.notify_verbosity = NotifyVerbosity::from_str(value.as_str().unwrap())?;
         }
+        n if n.eq(OPT_MEMPOOL_URL) => {
+            let mempool_url = value.as_str().unwrap().trim_end_matches('/');
+            if !mempool_url.starts_with("http://") && !mempool_url.starts_with("https://") {
+                return Err(anyhow!(
+                    "{OPT_MEMPOOL_URL} must start with `http://` or `https://`"
+                ));
+            }
+            config.mempool_url = mempool_url.to_string();
+        }
+        n if n.eq(OPT_UNKNOWN_POLICY) => {
+            config.unknown_policy = UnknownPolicy::from_str(value.as_str().unwrap())?;
+        }
+        n if n.eq(OPT_EXT_COMMAND) => config.ext_command = value.as_str().unwrap().to_string(),
+        n if n.eq(OPT_EXT_TIMEOUT) => {
+            let ext_timeout_s = u64::try_from(value.as_i64().unwrap())
+                .context(format!("{OPT_EXT_TIMEOUT} out of valid range"))?;
+            if ext_timeout_s == 0 {
+                return Err(anyhow!("{OPT_EXT_TIMEOUT} must be greater than 0"));
+            }
+            config.ext_timeout_s = ext_timeout_s;
+        }
+        n if n.eq(OPT_CACHE_TTL_PING) => config.cache_ttl_ping_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_GOSSIP) => config.cache_ttl_gossip_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_REACHABILITY) => {
+            config.cache_ttl_reachability_s = parse_ttl(n, value)?
+        }
+        n if n.eq(OPT_CACHE_TTL_AMBOSS) => config.cache_ttl_amboss_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_ONEML) => config.cache_ttl_oneml_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_MEMPOOL) => config.cache_ttl_mempool_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_EXT) => config.cache_ttl_ext_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_TTL_ERROR) => config.cache_ttl_error_s = parse_ttl(n, value)?,
+        n if n.eq(OPT_CACHE_SIZE) => {
+            let cache_size = u64::try_from(value.as_i64().unwrap())
+                .context(format!("{OPT_CACHE_SIZE} out of valid range"))?;
+            if cache_size == 0 {
+                return Err(anyhow!("{OPT_CACHE_SIZE} must be greater than 0"));
+            }
+            config.cache_size = cache_size;
+        }
+        n if n.eq(OPT_PREFETCH) => {
+            config.prefetch = match value {
+                options::Value::String(s) => s.parse()?,
+                options::Value::Boolean(b) => *b,
+                _ => return Err(anyhow!("{OPT_PREFETCH} must be a boolean")),
+            }
+        }
         _ => return Err(anyhow!("Unknown option: {name}")),
     }
     Ok(())
 }
 
+fn parse_ttl(name: &str, value: &options::Value) -> Result<u64, Error> {
+    u64::try_from(value.as_i64().unwrap()).context(format!("{name} out of valid range"))
+}
+
 fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value, Error> {
     match name {
         n if n.eq(OPT_BLOCK_MODE) => {
@@ -234,6 +571,14 @@ fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value,
                 Err(anyhow!("{OPT_NOTIFY_VERBOSITY} is not a string!"))
             }
         }
+        n if n.eq(OPT_UNKNOWN_POLICY) => {
+            if let Some(up_str) = value.as_str() {
+                UnknownPolicy::from_str(up_str)?;
+                Ok(options::Value::String(up_str.to_string()))
+            } else {
+                Err(anyhow!("{OPT_UNKNOWN_POLICY} is not a string!"))
+            }
+        }
         n if n.eq(OPT_CUSTOM_RULE) => {
             if let Some(cr_str) = value.as_str() {
                 parse_rule(cr_str)?;
@@ -242,7 +587,21 @@ fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value,
                 Err(anyhow!("{OPT_CUSTOM_RULE} is not a string!"))
             }
         }
-        n if n.eq(OPT_SMTP_PORT) | n.eq(OPT_PING_LENGTH) => {
+        n if n.eq(OPT_SMTP_PORT)
+            | n.eq(OPT_PING_LENGTH)
+            | n.eq(OPT_PING_COUNT)
+            | n.eq(OPT_PING_TIMEOUT)
+            | n.eq(OPT_EXT_TIMEOUT)
+            | n.eq(OPT_CACHE_TTL_PING)
+            | n.eq(OPT_CACHE_TTL_GOSSIP)
+            | n.eq(OPT_CACHE_TTL_REACHABILITY)
+            | n.eq(OPT_CACHE_TTL_AMBOSS)
+            | n.eq(OPT_CACHE_TTL_ONEML)
+            | n.eq(OPT_CACHE_TTL_MEMPOOL)
+            | n.eq(OPT_CACHE_TTL_EXT)
+            | n.eq(OPT_CACHE_TTL_ERROR)
+            | n.eq(OPT_CACHE_SIZE) =>
+        {
             if let Some(n_i64) = value.as_i64() {
                 return Ok(options::Value::Integer(n_i64));
             } else if let Some(n_str) = value.as_str() {
@@ -252,7 +611,7 @@ fn parse_option(name: &str, value: &serde_json::Value) -> Result<options::Value,
             }
             Err(anyhow!("{name} is not a valid integer!"))
         }
-        n if n.eq(OPT_LEAK_REASON) => match value {
+        n if n.eq(OPT_LEAK_REASON) | n.eq(OPT_PREFETCH) => match value {
             serde_json::Value::String(s) => Ok(options::Value::Boolean(s.parse()?)),
             serde_json::Value::Bool(b) => Ok(options::Value::Boolean(*b)),
             _ => Err(anyhow!("{name} must be a boolean")),
@@ -307,7 +666,7 @@ pub async fn setconfig_callback(
 
     activate_mail(&mut config);
 
-    if name.eq(OPT_CUSTOM_RULE) {
+    if name.eq(OPT_MEMPOOL_URL) || name.eq(OPT_EXT_COMMAND) {
         plugin.state().peerdata_cache.lock().clear();
     }
 
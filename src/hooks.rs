@@ -1,18 +1,38 @@
-use anyhow::Error;
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Error, anyhow};
 use cln_plugin::Plugin;
 use cln_rpc::{
     hooks::{
         actions::{Openchannel2Action, Openchannel2Result, OpenchannelAction, OpenchannelResult},
-        events::{Openchannel2Event, OpenchannelEvent},
+        events::{
+            Openchannel2Event,
+            Openchannel2Openchannel2,
+            OpenchannelEvent,
+            OpenchannelOpenchannel,
+        },
     },
     primitives::{Amount, PublicKey},
 };
 
 use crate::{
-    collect::collect_data,
+    collect::{collect_data, prefetch_data},
     notify::notify,
     parser::{evaluate_rule, parse_rule},
-    structs::{BlockMode, ChannelFlags, ClnrodParser, Config, NotifyVerbosity, PluginState},
+    structs::{
+        BlockMode,
+        ChannelFlags,
+        ChannelParams,
+        ClnrodParser,
+        Config,
+        NotifyVerbosity,
+        PluginState,
+        UnknownPolicy,
+    },
+    tasks::{record_connection, save_peerdata_cache, save_uptime_cache},
 };
 
 pub async fn openchannel_hook(
@@ -24,6 +44,7 @@ pub async fn openchannel_hook(
         event.openchannel.id,
         event.openchannel.funding_msat,
         parse_channel_flags(event.openchannel.channel_flags),
+        parse_openchannel_params(&event.openchannel),
     )
     .await
     {
@@ -67,6 +88,7 @@ pub async fn openchannel2_hook(
         event.openchannel2.id,
         event.openchannel2.their_funding_msat,
         parse_channel_flags(event.openchannel2.channel_flags),
+        parse_openchannel2_params(&event.openchannel2),
     )
     .await
     {
@@ -92,6 +114,7 @@ async fn release_hook(
     pubkey: PublicKey,
     their_funding_msat: Amount,
     channel_flags: ChannelFlags,
+    channel_params: ChannelParams,
 ) -> Result<bool, String> {
     let pubkey_list = plugin.state().pubkey_list.lock().clone();
     let config = plugin.state().config.lock().clone();
@@ -109,6 +132,7 @@ async fn release_hook(
             pubkey,
             their_funding_msat,
             channel_flags,
+            channel_params,
             &config.custom_rule,
             config.ping_length,
         )
@@ -127,19 +151,32 @@ async fn release_hook(
                 return Err(create_reject_response(&config, "internal error"));
             }
         };
-        let parser = ClnrodParser::new();
-        match evaluate_rule(&parser, parse_rule(&config.custom_rule).unwrap(), &data) {
-            Ok(o) => Some(o),
-            Err(e) => {
-                notify(
-                    &plugin,
-                    "Clnrod channel rejected. EVALUATE_RULE ERROR",
-                    &e.to_string(),
-                    Some(pubkey),
-                    NotifyVerbosity::Error,
-                )
-                .await;
-                return Err(create_reject_response(&config, "internal error"));
+        if data.peerinfo.node_known == Some(false)
+            && config.unknown_policy != UnknownPolicy::Evaluate
+        {
+            log::info!(
+                "{pubkey} is unknown to gossip, unknown policy: {}",
+                config.unknown_policy
+            );
+            Some((
+                config.unknown_policy == UnknownPolicy::Accept,
+                Some("peer unknown to gossip".to_string()),
+            ))
+        } else {
+            let parser = ClnrodParser::new();
+            match evaluate_rule(&parser, parse_rule(&config.custom_rule).unwrap(), &data) {
+                Ok(o) => Some(o),
+                Err(e) => {
+                    notify(
+                        &plugin,
+                        "Clnrod channel rejected. EVALUATE_RULE ERROR",
+                        &e.to_string(),
+                        Some(pubkey),
+                        NotifyVerbosity::Error,
+                    )
+                    .await;
+                    return Err(create_reject_response(&config, "internal error"));
+                }
             }
         }
     } else {
@@ -262,6 +299,46 @@ fn parse_channel_flags(channel_flags: u8) -> ChannelFlags {
     ChannelFlags { public }
 }
 
+fn parse_openchannel_params(openchannel: &OpenchannelOpenchannel) -> ChannelParams {
+    ChannelParams {
+        push_msat: openchannel.push_msat.msat(),
+        dust_limit_msat: openchannel.dust_limit_msat.msat(),
+        max_htlc_value_in_flight_msat: openchannel.max_htlc_value_in_flight_msat.msat(),
+        channel_reserve_msat: openchannel.channel_reserve_msat.msat(),
+        htlc_minimum_msat: openchannel.htlc_minimum_msat.msat(),
+        feerate_per_kw: u64::from(openchannel.feerate_per_kw),
+        funding_feerate_per_kw: 0,
+        to_self_delay: u64::from(openchannel.to_self_delay),
+        max_accepted_htlcs: u64::from(openchannel.max_accepted_htlcs),
+        locktime: 0,
+        requested_lease_msat: 0,
+        channel_type: openchannel
+            .channel_type
+            .as_ref()
+            .map_or(Vec::new(), |ct| ct.bits.clone()),
+    }
+}
+
+fn parse_openchannel2_params(openchannel2: &Openchannel2Openchannel2) -> ChannelParams {
+    ChannelParams {
+        push_msat: 0,
+        dust_limit_msat: openchannel2.dust_limit_msat.msat(),
+        max_htlc_value_in_flight_msat: openchannel2.max_htlc_value_in_flight_msat.msat(),
+        channel_reserve_msat: 0,
+        htlc_minimum_msat: openchannel2.htlc_minimum_msat.msat(),
+        feerate_per_kw: u64::from(openchannel2.commitment_feerate_per_kw),
+        funding_feerate_per_kw: u64::from(openchannel2.funding_feerate_per_kw),
+        to_self_delay: u64::from(openchannel2.to_self_delay),
+        max_accepted_htlcs: u64::from(openchannel2.max_accepted_htlcs),
+        locktime: u64::from(openchannel2.locktime),
+        requested_lease_msat: openchannel2.requested_lease_msat.map_or(0, |l| l.msat()),
+        channel_type: openchannel2
+            .channel_type
+            .as_ref()
+            .map_or(Vec::new(), |ct| ct.bits.clone()),
+    }
+}
+
 fn create_reject_response(config: &Config, reason: &str) -> String {
     if config.leak_reason {
         format!("{} Reason: {}", config.deny_message, reason)
@@ -269,3 +346,64 @@ fn create_reject_response(config: &Config, reason: &str) -> String {
         config.deny_message.clone()
     }
 }
+
+pub async fn connect_notification(
+    plugin: Plugin<PluginState>,
+    notification: serde_json::Value,
+) -> Result<(), Error> {
+    let pubkey = notification_peer_id(&notification, "connect")?;
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    {
+        let mut uptime_cache = plugin.state().uptime_cache.lock();
+        record_connection(uptime_cache.entry(pubkey).or_default(), unix_now_s, true);
+    }
+    tokio::spawn(async move {
+        if let Err(e) = prefetch_data(plugin, pubkey).await {
+            log::info!("Could not prefetch data of {pubkey}: {e}");
+        }
+    });
+    Ok(())
+}
+
+pub async fn disconnect_notification(
+    plugin: Plugin<PluginState>,
+    notification: serde_json::Value,
+) -> Result<(), Error> {
+    let pubkey = notification_peer_id(&notification, "disconnect")?;
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let mut uptime_cache = plugin.state().uptime_cache.lock();
+    if let Some(uptime) = uptime_cache.get_mut(&pubkey) {
+        record_connection(uptime, unix_now_s, false);
+    }
+    Ok(())
+}
+
+pub async fn shutdown_notification(
+    plugin: Plugin<PluginState>,
+    _notification: serde_json::Value,
+) -> Result<(), Error> {
+    if let Err(e) = save_uptime_cache(&plugin).await {
+        log::warn!("Could not save uptime history on shutdown: {e}");
+    }
+    if let Err(e) = save_peerdata_cache(&plugin).await {
+        log::warn!("Could not save cached data on shutdown: {e}");
+    }
+    plugin.shutdown()
+}
+
+fn notification_peer_id(notification: &serde_json::Value, topic: &str) -> Result<PublicKey, Error> {
+    // older CLN versions send the fields without the topic wrapper
+    let id = notification
+        .get(topic)
+        .unwrap_or(notification)
+        .get("id")
+        .and_then(|i| i.as_str())
+        .ok_or_else(|| anyhow!("{topic} notification without peer id"))?;
+    Ok(PublicKey::from_str(id)?)
+}
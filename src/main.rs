@@ -14,9 +14,15 @@ use cln_plugin::{
     },
 };
 use config::{read_config, setconfig_callback};
-use hooks::{openchannel_hook, openchannel2_hook};
+use hooks::{
+    connect_notification,
+    disconnect_notification,
+    openchannel_hook,
+    openchannel2_hook,
+    shutdown_notification,
+};
 use pest_derive::Parser;
-use rpc::{clnrod_reload, clnrod_testmail, clnrod_testping, clnrod_testrule};
+use rpc::{clnrod_listclusters, clnrod_reload, clnrod_testmail, clnrod_testping, clnrod_testrule};
 use structs::PluginState;
 use tokio::time;
 
@@ -38,6 +44,8 @@ const OPT_LEAK_REASON: &str = "clnrod-leakreason";
 const OPT_BLOCK_MODE: &str = "clnrod-blockmode";
 const OPT_CUSTOM_RULE: &str = "clnrod-customrule";
 const OPT_PING_LENGTH: &str = "clnrod-pinglength";
+const OPT_PING_COUNT: &str = "clnrod-pingcount";
+const OPT_PING_TIMEOUT: &str = "clnrod-pingtimeout";
 const OPT_SMTP_USERNAME: &str = "clnrod-smtp-username";
 const OPT_SMTP_PASSWORD: &str = "clnrod-smtp-password";
 const OPT_SMTP_SERVER: &str = "clnrod-smtp-server";
@@ -45,6 +53,20 @@ const OPT_SMTP_PORT: &str = "clnrod-smtp-port";
 const OPT_EMAIL_FROM: &str = "clnrod-email-from";
 const OPT_EMAIL_TO: &str = "clnrod-email-to";
 const OPT_NOTIFY_VERBOSITY: &str = "clnrod-notify-verbosity";
+const OPT_MEMPOOL_URL: &str = "clnrod-mempool-url";
+const OPT_UNKNOWN_POLICY: &str = "clnrod-unknown-policy";
+const OPT_EXT_COMMAND: &str = "clnrod-ext-command";
+const OPT_EXT_TIMEOUT: &str = "clnrod-ext-timeout";
+const OPT_CACHE_TTL_PING: &str = "clnrod-cache-ttl-ping";
+const OPT_CACHE_TTL_GOSSIP: &str = "clnrod-cache-ttl-gossip";
+const OPT_CACHE_TTL_REACHABILITY: &str = "clnrod-cache-ttl-reachability";
+const OPT_CACHE_TTL_AMBOSS: &str = "clnrod-cache-ttl-amboss";
+const OPT_CACHE_TTL_ONEML: &str = "clnrod-cache-ttl-oneml";
+const OPT_CACHE_TTL_MEMPOOL: &str = "clnrod-cache-ttl-mempool";
+const OPT_CACHE_TTL_EXT: &str = "clnrod-cache-ttl-ext";
+const OPT_CACHE_TTL_ERROR: &str = "clnrod-cache-ttl-error";
+const OPT_CACHE_SIZE: &str = "clnrod-cache-size";
+const OPT_PREFETCH: &str = "clnrod-prefetch";
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
@@ -86,6 +108,20 @@ async fn main() -> Result<(), anyhow::Error> {
     )
     .dynamic();
 
+    let opt_ping_count: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_PING_COUNT,
+        i64::try_from(config_defaults.ping_count)?,
+        "Number of pings sent to a peer for the ping variables",
+    )
+    .dynamic();
+
+    let opt_ping_timeout: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_PING_TIMEOUT,
+        i64::from(config_defaults.ping_timeout_ms),
+        "Time in ms after which a single ping counts as lost",
+    )
+    .dynamic();
+
     let opt_smtp_username: StringConfigOption =
         ConfigOption::new_str_no_default(OPT_SMTP_USERNAME, "Set smtp username").dynamic();
     let opt_smtp_password: StringConfigOption =
@@ -107,12 +143,110 @@ async fn main() -> Result<(), anyhow::Error> {
     )
     .dynamic();
 
+    let opt_mempool_url: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_MEMPOOL_URL,
+        "Base url of a mempool.space compatible explorer with lightning backend",
+    )
+    .dynamic();
+
+    let default_unknown_policy = config_defaults.unknown_policy.to_string();
+    let opt_unknown_policy: DefaultStringConfigOption = ConfigOption::new_str_with_default(
+        OPT_UNKNOWN_POLICY,
+        &default_unknown_policy,
+        "What to do with peers unknown to your gossip. One of: 'reject', 'accept', 'evaluate'",
+    )
+    .dynamic();
+
+    let opt_ext_command: StringConfigOption = ConfigOption::new_str_no_default(
+        OPT_EXT_COMMAND,
+        "Executable that returns extra ext_ variables for the custom rule, see README.md",
+    )
+    .dynamic();
+
+    let opt_ext_timeout: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_EXT_TIMEOUT,
+        i64::try_from(config_defaults.ext_timeout_s)?,
+        "Time limit in seconds for the ext command",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_ping: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_PING,
+        i64::try_from(config_defaults.cache_ttl_ping_s)?,
+        "Time in seconds ping results are cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_gossip: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_GOSSIP,
+        i64::try_from(config_defaults.cache_ttl_gossip_s)?,
+        "Time in seconds data from your gossip is cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_reachability: DefaultIntegerConfigOption =
+        ConfigOption::new_i64_with_default(
+            OPT_CACHE_TTL_REACHABILITY,
+            i64::try_from(config_defaults.cache_ttl_reachability_s)?,
+            "Time in seconds reachability probe results are cached",
+        )
+        .dynamic();
+
+    let opt_cache_ttl_amboss: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_AMBOSS,
+        i64::try_from(config_defaults.cache_ttl_amboss_s)?,
+        "Time in seconds data from the Amboss API is cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_oneml: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_ONEML,
+        i64::try_from(config_defaults.cache_ttl_oneml_s)?,
+        "Time in seconds data from the 1ML API is cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_mempool: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_MEMPOOL,
+        i64::try_from(config_defaults.cache_ttl_mempool_s)?,
+        "Time in seconds data from the mempool API is cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_ext: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_EXT,
+        i64::try_from(config_defaults.cache_ttl_ext_s)?,
+        "Time in seconds results of the ext command are cached",
+    )
+    .dynamic();
+
+    let opt_cache_ttl_error: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_TTL_ERROR,
+        i64::try_from(config_defaults.cache_ttl_error_s)?,
+        "Time in seconds errors of any data source are cached",
+    )
+    .dynamic();
+
+    let opt_cache_size: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+        OPT_CACHE_SIZE,
+        i64::try_from(config_defaults.cache_size)?,
+        "Maximum number of peers in the cache",
+    )
+    .dynamic();
+
+    let opt_prefetch: DefaultBooleanConfigOption = ConfigOption::new_bool_with_default(
+        OPT_PREFETCH,
+        config_defaults.prefetch,
+        "Collect the data for the custom rule in the background when a peer connects",
+    )
+    .dynamic();
+
     let confplugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .rpcmethod("clnrod-reload", "Reloads rules from file.", clnrod_reload)
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("clnrod-testrule", clnrod_testrule)
                 .description("Test custom rule")
-                .usage("pubkey public their_funding_sat rule"),
+                .usage("pubkey public their_funding_sat rule [channel_params]"),
         )
         .rpcmethod("clnrod-testmail", "Test mail config", clnrod_testmail)
         .rpcmethod_from_builder(
@@ -120,6 +254,11 @@ async fn main() -> Result<(), anyhow::Error> {
                 .description("Test the ping to a node")
                 .usage("pubkey [count] [length]"),
         )
+        .rpcmethod_from_builder(
+            RpcMethodBuilder::new("clnrod-listclusters", clnrod_listclusters)
+                .description("List nodes in your gossip that likely have the same operator")
+                .usage("[pubkey]"),
+        )
         .rpcmethod_from_builder(
             RpcMethodBuilder::new("clnrod-managelists", clnrod_managelists)
                 .description("Manage allow, block or zeroconf list files")
@@ -131,6 +270,8 @@ async fn main() -> Result<(), anyhow::Error> {
         .option(opt_block_mode)
         .option(opt_custom_rule)
         .option(opt_ping_length)
+        .option(opt_ping_count)
+        .option(opt_ping_timeout)
         .option(opt_smtp_username)
         .option(opt_smtp_password)
         .option(opt_smtp_server)
@@ -138,8 +279,25 @@ async fn main() -> Result<(), anyhow::Error> {
         .option(opt_email_from)
         .option(opt_email_to)
         .option(opt_notify_verbosity)
+        .option(opt_mempool_url)
+        .option(opt_unknown_policy)
+        .option(opt_ext_command)
+        .option(opt_ext_timeout)
+        .option(opt_cache_ttl_ping)
+        .option(opt_cache_ttl_gossip)
+        .option(opt_cache_ttl_reachability)
+        .option(opt_cache_ttl_amboss)
+        .option(opt_cache_ttl_oneml)
+        .option(opt_cache_ttl_mempool)
+        .option(opt_cache_ttl_ext)
+        .option(opt_cache_ttl_error)
+        .option(opt_cache_size)
+        .option(opt_prefetch)
         .hook_typed("openchannel", openchannel_hook)
         .hook_typed("openchannel2", openchannel2_hook)
+        .subscribe("connect", connect_notification)
+        .subscribe("disconnect", disconnect_notification)
+        .subscribe("shutdown", shutdown_notification)
         .dynamic()
         .configure()
         .await?
@@ -167,6 +325,50 @@ async fn main() -> Result<(), anyhow::Error> {
                     time::sleep(Duration::from_secs(60 * 60)).await;
                 }
             });
+            let graphclone = plugin.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_secs(60)).await;
+                loop {
+                    match tasks::refresh_graph_cache(graphclone.clone()).await {
+                        Ok(()) => (),
+                        Err(e) => log::warn!("Error in refresh_graph_cache thread: {e}"),
+                    }
+                    time::sleep(Duration::from_secs(60 * 60)).await;
+                }
+            });
+            let historyclone = plugin.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_secs(60)).await;
+                loop {
+                    match tasks::refresh_history_cache(historyclone.clone()).await {
+                        Ok(()) => (),
+                        Err(e) => log::warn!("Error in refresh_history_cache thread: {e}"),
+                    }
+                    time::sleep(Duration::from_secs(60 * 10)).await;
+                }
+            });
+            let uptimeclone = plugin.clone();
+            tokio::spawn(async move {
+                let mut round: u64 = 0;
+                loop {
+                    match tasks::sample_uptime(uptimeclone.clone(), round % 12 == 0).await {
+                        Ok(()) => (),
+                        Err(e) => log::warn!("Error in sample_uptime thread: {e}"),
+                    }
+                    round += 1;
+                    time::sleep(Duration::from_secs(60 * 5)).await;
+                }
+            });
+            let cacheclone = plugin.clone();
+            tokio::spawn(async move {
+                loop {
+                    time::sleep(Duration::from_secs(60 * 10)).await;
+                    match tasks::save_peerdata_cache(&cacheclone).await {
+                        Ok(()) => (),
+                        Err(e) => log::warn!("Error in save_peerdata_cache thread: {e}"),
+                    }
+                }
+            });
             plugin.join().await
         }
         _ => Err(anyhow!("Error starting clnrod!")),
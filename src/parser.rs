@@ -10,7 +10,8 @@ use pest::{
 };
 
 use crate::{
-    structs::{ClnrodParser, PeerData},
+    collect::check_feature,
+    structs::{Cidr, ClnrodParser, PeerData, RuleNames, RuleValue},
     Rule,
     RulesParser,
 };
@@ -38,6 +39,35 @@ pub fn parse_rule(rule: &str) -> Result<Pairs<'_, Rule>, Error> {
     }
 }
 
+// Names of the files used as `file:<name>` argument in the rule
+pub fn cidr_files(rule: &str) -> Result<Vec<String>, Error> {
+    Ok(parse_rule(rule)?
+        .flatten()
+        .filter(|p| p.as_rule() == Rule::function_call)
+        .filter_map(|p| p.into_inner().find(|a| a.as_rule() == Rule::STRING))
+        .filter_map(|a| {
+            a.into_inner()
+                .next()
+                .unwrap()
+                .as_str()
+                .strip_prefix("file:")
+                .map(str::to_string)
+        })
+        .collect())
+}
+
+// Names of the variables and functions in the rule, to only collect the
+// data that is needed
+pub fn rule_names(rule: &str) -> Result<RuleNames, Error> {
+    Ok(RuleNames(
+        parse_rule(rule)?
+            .flatten()
+            .filter(|p| matches!(p.as_rule(), Rule::VARIABLE | Rule::FUNCTION))
+            .map(|p| p.as_str().to_ascii_lowercase())
+            .collect(),
+    ))
+}
+
 pub fn evaluate_rule(
     parser: &ClnrodParser,
     rule: Pairs<Rule>,
@@ -113,17 +143,43 @@ fn evaluate_comparison(
     operator: &Pair<Rule>,
     variables: &PeerData,
 ) -> Result<(bool, Option<String>), Error> {
-    let left_value = evaluate_value(left, variables)?;
     let right_value = evaluate_value(right, variables)?;
+    // unknown ext_ variables fail every comparison, so a crashing or slow
+    // command can not make `ext_flagged != 1` true
+    if is_unknown_ext_variable(left, variables) {
+        let rej_match = format!("{} {} {}", left.as_str(), operator.as_str(), right_value);
+        debug!("Compared: {rej_match} Result: false (unknown)");
+        return Ok((false, Some(format!("{rej_match} -> actual: unknown"))));
+    }
+    let left_value = evaluate_value(left, variables)?;
 
-    let result = match operator.as_rule() {
-        Rule::equal => left_value == right_value,
-        Rule::unequal => left_value != right_value,
-        Rule::greater => left_value > right_value,
-        Rule::lesser => left_value < right_value,
-        Rule::gte => left_value >= right_value,
-        Rule::lte => left_value <= right_value,
-        e => return Err(anyhow!("unknown comparison operator: {e:?}")),
+    let result = match (&left_value, &right_value) {
+        (RuleValue::Integer(l), RuleValue::Integer(r)) => match operator.as_rule() {
+            Rule::equal => l == r,
+            Rule::unequal => l != r,
+            Rule::greater => l > r,
+            Rule::lesser => l < r,
+            Rule::gte => l >= r,
+            Rule::lte => l <= r,
+            e => return Err(anyhow!("unknown comparison operator: {e:?}")),
+        },
+        (RuleValue::Text(l), RuleValue::Text(r)) => match operator.as_rule() {
+            Rule::equal => l.eq_ignore_ascii_case(r),
+            Rule::unequal => !l.eq_ignore_ascii_case(r),
+            _ => {
+                return Err(anyhow!(
+                    "Only `==` and `!=` are supported for text values, got: `{}`",
+                    operator.as_str()
+                ));
+            }
+        },
+        _ => {
+            return Err(anyhow!(
+                "Can not compare `{}` with `{}`: mismatched value types",
+                left.as_str(),
+                right.as_str()
+            ));
+        }
     };
 
     let rej_match = format!("{} {} {}", left.as_str(), operator.as_str(), right_value);
@@ -133,183 +189,77 @@ fn evaluate_comparison(
     if result {
         Ok((result, None))
     } else {
-        Ok((result, Some(format!("{rej_match} -> actual: {left_value}"))))
+        let mut reject_reason = format!("{rej_match} -> actual: {left_value}");
+        if left
+            .as_str()
+            .eq_ignore_ascii_case("cln_alias_impersonation")
+        {
+            if let Some(victim) = variables.peerinfo.impersonated_node {
+                reject_reason.push_str(&format!(" (impersonates {victim})"));
+            }
+        }
+        Ok((result, Some(reject_reason)))
     }
 }
 
-fn evaluate_value(pair: &Pair<Rule>, variables: &PeerData) -> Result<u64, Error> {
+fn is_unknown_ext_variable(pair: &Pair<Rule>, variables: &PeerData) -> bool {
+    let name = pair.as_str().to_ascii_lowercase();
+    pair.as_rule() == Rule::VARIABLE
+        && name.starts_with("ext_")
+        && variables
+            .ext_data
+            .as_ref()
+            .is_none_or(|e| !e.contains_key(&name))
+}
+
+fn evaluate_value(pair: &Pair<Rule>, variables: &PeerData) -> Result<RuleValue, Error> {
     match pair.as_rule() {
-        Rule::INTEGER => Ok(pair.as_str().parse::<u64>().unwrap()),
+        Rule::INTEGER => Ok(RuleValue::Integer(pair.as_str().parse::<u64>()?)),
         Rule::VARIABLE => match pair.as_str() {
-            p if p.eq_ignore_ascii_case("their_funding_sat") => {
-                Ok(variables.openinginfo.their_funding_sat)
-            }
-            p if p.eq_ignore_ascii_case("cln_node_capacity_sat") => {
-                Ok(variables.peerinfo.node_capacity_sat.unwrap())
-            }
-            p if p.eq_ignore_ascii_case("cln_channel_count") => {
-                Ok(variables.peerinfo.channel_count.unwrap())
-            }
-            p if p.eq_ignore_ascii_case("cln_multi_channel_count") => {
-                Ok(variables.openinginfo.multi_channel_count)
-            }
-            p if p.eq_ignore_ascii_case("cln_has_clearnet") => {
-                Ok(u64::from(variables.peerinfo.has_clearnet.unwrap()))
-            }
-            p if p.eq_ignore_ascii_case("cln_has_tor") => {
-                Ok(u64::from(variables.peerinfo.has_tor.unwrap()))
-            }
-            p if p.eq_ignore_ascii_case("cln_anchor_support") => {
-                Ok(u64::from(variables.peerinfo.anchor_support.unwrap()))
-            }
-            p if p.eq_ignore_ascii_case("public") => {
-                Ok(u64::from(variables.openinginfo.channel_flags.public))
-            }
-            p if p.eq_ignore_ascii_case("ping") => Ok(u64::from(variables.ping.unwrap())),
-            p if p.eq_ignore_ascii_case("oneml_capacity") => Ok(variables
-                .oneml_data
-                .as_ref()
-                .unwrap()
-                .capacity
-                .unwrap_or(u64::MAX)),
-            p if p.eq_ignore_ascii_case("oneml_channelcount") => Ok(variables
-                .oneml_data
-                .as_ref()
-                .unwrap()
-                .channelcount
-                .unwrap_or(u64::MAX)),
-            p if p.eq_ignore_ascii_case("oneml_age") => Ok(variables
-                .oneml_data
-                .as_ref()
-                .unwrap()
-                .age
-                .unwrap_or(u64::MAX)),
-            p if p.eq_ignore_ascii_case("oneml_growth") => Ok(variables
-                .oneml_data
-                .as_ref()
-                .unwrap()
-                .growth
-                .unwrap_or(u64::MAX)),
-            p if p.eq_ignore_ascii_case("oneml_availability") => Ok(variables
-                .oneml_data
-                .as_ref()
-                .unwrap()
-                .availability
-                .unwrap_or(u64::MAX)),
-            p if p.eq_ignore_ascii_case("amboss_capacity_rank") => {
-                if let Some(metrics) = &variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .graph_info
-                    .metrics
-                {
-                    Ok(metrics.capacity_rank)
-                } else {
-                    Ok(u64::MAX)
-                }
-            }
-            p if p.eq_ignore_ascii_case("amboss_channels_rank") => {
-                if let Some(metrics) = &variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .graph_info
-                    .metrics
-                {
-                    Ok(metrics.channels_rank)
-                } else {
-                    Ok(u64::MAX)
-                }
-            }
-            p if p.eq_ignore_ascii_case("amboss_has_email") => Ok(u64::from(
-                variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
-                    .as_ref()
-                    .is_some_and(|i| i.email.is_some()),
-            )),
-            p if p.eq_ignore_ascii_case("amboss_has_linkedin") => Ok(u64::from(
-                variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
-                    .as_ref()
-                    .is_some_and(|i| i.linkedin.is_some()),
-            )),
-            p if p.eq_ignore_ascii_case("amboss_has_nostr") => Ok(u64::from(
-                variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
-                    .as_ref()
-                    .is_some_and(|i| i.nostr.is_some()),
-            )),
-            p if p.eq_ignore_ascii_case("amboss_has_telegram") => Ok(u64::from(
+            p if p.eq_ignore_ascii_case("mempool_country") => Ok(RuleValue::Text(
                 variables
-                    .amboss_data
+                    .mempool_data
                     .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
-                    .as_ref()
-                    .is_some_and(|i| i.telegram.is_some()),
+                    .ok_or_else(|| anyhow!("No mempool data collected for {p}"))?
+                    .iso_code
+                    .clone()
+                    .unwrap_or_default(),
             )),
-            p if p.eq_ignore_ascii_case("amboss_has_twitter") => Ok(u64::from(
+            p if p.eq_ignore_ascii_case("geo_country") => Ok(RuleValue::Text(
                 variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
+                    .geo
                     .as_ref()
-                    .is_some_and(|i| i.twitter.is_some()),
+                    .ok_or_else(|| anyhow!("No geo data collected for {p}"))?
+                    .country
+                    .clone(),
             )),
-            p if p.eq_ignore_ascii_case("amboss_has_website") => Ok(u64::from(
+            p if p.to_ascii_lowercase().starts_with("ext_") => variables
+                .ext_data
+                .as_ref()
+                .and_then(|e| e.get(&p.to_ascii_lowercase()))
+                .cloned()
+                .ok_or_else(|| anyhow!("No ext data for {p}")),
+            p if p.eq_ignore_ascii_case("cln_impl") => Ok(RuleValue::Text(
                 variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .info
-                    .as_ref()
-                    .is_some_and(|i| i.website.is_some()),
+                    .peerinfo
+                    .implementation
+                    .clone()
+                    .ok_or_else(|| anyhow!("No gossip data collected for {p}"))?,
             )),
-            p if p.eq_ignore_ascii_case("amboss_terminal_web_rank") => {
-                if let Some(term_web) = &variables
-                    .amboss_data
-                    .as_ref()
-                    .unwrap()
-                    .get_node
-                    .socials
-                    .lightning_labs
-                    .terminal_web
-                {
-                    Ok(term_web.position)
-                } else {
-                    Ok(u64::MAX)
-                }
-            }
-            _ => Err(anyhow!("Invalid variable name: {}", pair.as_str())),
+            p => Ok(RuleValue::Integer(evaluate_variable(p, variables)?)),
         },
+        Rule::function_call => evaluate_function(pair, variables),
+        Rule::STRING => Ok(RuleValue::Text(
+            pair.clone()
+                .into_inner()
+                .next()
+                .unwrap()
+                .as_str()
+                .to_string(),
+        )),
         Rule::BOOLEAN => match pair.as_str() {
-            v if v.eq_ignore_ascii_case("true") => Ok(1),
-            v if v.eq_ignore_ascii_case("false") => Ok(0),
+            v if v.eq_ignore_ascii_case("true") => Ok(RuleValue::Integer(1)),
+            v if v.eq_ignore_ascii_case("false") => Ok(RuleValue::Integer(0)),
             e => Err(anyhow!("Invalid Boolean atomic: {e}")),
         },
         Rule::value => {
@@ -320,3 +270,532 @@ fn evaluate_value(pair: &Pair<Rule>, variables: &PeerData) -> Result<u64, Error>
         e => Err(anyhow!("Unexpected rule:{e:?}")),
     }
 }
+
+fn evaluate_function(pair: &Pair<Rule>, variables: &PeerData) -> Result<RuleValue, Error> {
+    let mut inner_pairs = pair.clone().into_inner();
+    let function = inner_pairs.next().unwrap();
+    let argument = inner_pairs.next().unwrap();
+    match function.as_str() {
+        f if f.eq_ignore_ascii_case("feature") => {
+            let bit = match argument.as_rule() {
+                Rule::INTEGER => argument
+                    .as_str()
+                    .parse::<u16>()
+                    .map_err(|e| anyhow!("Invalid feature bit `{}`: {e}", argument.as_str()))?,
+                Rule::FEATURE_NAME => feature_name_to_bit(argument.as_str())?,
+                e => return Err(anyhow!("Unexpected argument for feature(): {e:?}")),
+            };
+            let even_bit = bit - (bit % 2);
+            let is_set = if let Some(features) = &variables.peerinfo.features {
+                check_feature(features, vec![even_bit, even_bit + 1])?
+            } else {
+                false
+            };
+            Ok(RuleValue::Integer(u64::from(is_set)))
+        }
+        f if f.eq_ignore_ascii_case("chan_type_bit") => {
+            let bit = match argument.as_rule() {
+                Rule::INTEGER => argument.as_str().parse::<u32>().map_err(|e| {
+                    anyhow!("Invalid channel type bit `{}`: {e}", argument.as_str())
+                })?,
+                Rule::FEATURE_NAME => u32::from(feature_name_to_bit(argument.as_str())?),
+                e => return Err(anyhow!("Unexpected argument for chan_type_bit(): {e:?}")),
+            };
+            Ok(RuleValue::Integer(u64::from(has_channel_type_bit(
+                &variables.openinginfo.channel_params.channel_type,
+                bit,
+            ))))
+        }
+        f if f.eq_ignore_ascii_case("conn_in_cidr") => {
+            let cidrs = cidr_argument(&argument, variables)?;
+            let in_cidr = variables
+                .connection
+                .as_ref()
+                .and_then(|c| c.ip)
+                .is_some_and(|ip| cidrs.iter().any(|c| c.contains(&ip)));
+            Ok(RuleValue::Integer(u64::from(in_cidr)))
+        }
+        f if f.eq_ignore_ascii_case("addr_in_cidr") => {
+            let cidrs = cidr_argument(&argument, variables)?;
+            let in_cidr = variables
+                .peerinfo
+                .addresses
+                .as_ref()
+                .is_some_and(|a| a.iter().any(|ip| cidrs.iter().any(|c| c.contains(ip))));
+            Ok(RuleValue::Integer(u64::from(in_cidr)))
+        }
+        _ => Err(anyhow!("Invalid function name: {}", function.as_str())),
+    }
+}
+
+fn cidr_argument(argument: &Pair<Rule>, variables: &PeerData) -> Result<Vec<Cidr>, Error> {
+    if argument.as_rule() != Rule::STRING {
+        return Err(anyhow!(
+            "Expected comma separated CIDRs or `file:<name>` in double quotes, got: {}",
+            argument.as_str()
+        ));
+    }
+    let text = argument.clone().into_inner().next().unwrap().as_str();
+    if let Some(file_name) = text.strip_prefix("file:") {
+        variables
+            .cidr_lists
+            .get(file_name)
+            .cloned()
+            .ok_or_else(|| anyhow!("CIDR file {file_name} not loaded"))
+    } else {
+        text.split(',').map(str::parse::<Cidr>).collect()
+    }
+}
+
+fn has_channel_type_bit(channel_type: &[u32], bit: u32) -> bool {
+    let even_bit = bit - (bit % 2);
+    channel_type.contains(&even_bit) || channel_type.contains(&(even_bit + 1))
+}
+
+fn feature_name_to_bit(name: &str) -> Result<u16, Error> {
+    match name.to_ascii_lowercase().as_str() {
+        "anchors" => Ok(22),
+        "route_blinding" => Ok(24),
+        "dual_fund" => Ok(28),
+        "scid_alias" => Ok(46),
+        "zeroconf" => Ok(50),
+        "splicing" => Ok(62),
+        _ => Err(anyhow!("Invalid feature name: {name}")),
+    }
+}
+
+fn evaluate_variable(name: &str, variables: &PeerData) -> Result<u64, Error> {
+    // data that was not collected, e.g. a source that failed
+    let unknown = || anyhow!("{name} unknown");
+    match name {
+        p if p.eq_ignore_ascii_case("their_funding_sat") => {
+            Ok(variables.openinginfo.their_funding_sat)
+        }
+        p if p.eq_ignore_ascii_case("cln_node_known") => Ok(u64::from(
+            variables.peerinfo.node_known.ok_or_else(unknown)?,
+        )),
+        p if p.eq_ignore_ascii_case("cln_node_capacity_sat") => {
+            Ok(variables.peerinfo.node_capacity_sat.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_channel_count") => {
+            Ok(variables.peerinfo.channel_count.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_multi_channel_count") => {
+            Ok(variables.openinginfo.multi_channel_count)
+        }
+        p if p.eq_ignore_ascii_case("cln_has_clearnet") => Ok(u64::from(
+            variables.peerinfo.has_clearnet.ok_or_else(unknown)?,
+        )),
+        p if p.eq_ignore_ascii_case("cln_has_tor") => {
+            Ok(u64::from(variables.peerinfo.has_tor.ok_or_else(unknown)?))
+        }
+        p if p.eq_ignore_ascii_case("cln_clearnet_reachable") => Ok(u64::from(
+            variables
+                .reachability
+                .as_ref()
+                .ok_or_else(unknown)?
+                .reachable,
+        )),
+        p if p.eq_ignore_ascii_case("cln_reachable_address_count") => Ok(variables
+            .reachability
+            .as_ref()
+            .ok_or_else(unknown)?
+            .address_count),
+        p if p.eq_ignore_ascii_case("cln_anchor_support") => Ok(u64::from(
+            variables.peerinfo.anchor_support.ok_or_else(unknown)?,
+        )),
+        p if p.eq_ignore_ascii_case("cln_node_age_blocks") => {
+            Ok(variables.peerinfo.node_age_blocks.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_last_update_age_s") => {
+            Ok(variables.peerinfo.last_update_age_s.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_median_fee_ppm") => {
+            Ok(variables.peerinfo.median_fee_ppm.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_max_fee_ppm") => {
+            Ok(variables.peerinfo.max_fee_ppm.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_median_base_fee_msat") => Ok(variables
+            .peerinfo
+            .median_base_fee_msat
+            .ok_or_else(unknown)?),
+        p if p.eq_ignore_ascii_case("cln_zero_base_fee_ratio") => {
+            Ok(variables.peerinfo.zero_base_fee_ratio.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_median_channel_sat") => {
+            Ok(variables.peerinfo.median_channel_sat.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_min_channel_sat") => {
+            Ok(variables.peerinfo.min_channel_sat.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_max_channel_sat") => {
+            Ok(variables.peerinfo.max_channel_sat.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_active_channel_ratio") => Ok(variables
+            .peerinfo
+            .active_channel_ratio
+            .ok_or_else(unknown)?),
+        p if p.eq_ignore_ascii_case("cln_hops_from_us") => {
+            Ok(variables.peerinfo.hops_from_us.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_common_peers") => {
+            Ok(variables.peerinfo.common_peers.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_alias_impersonation") => {
+            Ok(u64::from(variables.peerinfo.impersonated_node.is_some()))
+        }
+        p if p.eq_ignore_ascii_case("cln_capacity_percentile") => {
+            Ok(variables.peerinfo.capacity_percentile.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_channels_percentile") => {
+            Ok(variables.peerinfo.channels_percentile.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("cln_centrality_rank") => {
+            Ok(variables.peerinfo.centrality_rank.ok_or_else(unknown)?)
+        }
+        p if p.eq_ignore_ascii_case("public") => {
+            Ok(u64::from(variables.openinginfo.channel_flags.public))
+        }
+        p if p.eq_ignore_ascii_case("push_msat") => {
+            Ok(variables.openinginfo.channel_params.push_msat)
+        }
+        p if p.eq_ignore_ascii_case("dust_limit_msat") => {
+            Ok(variables.openinginfo.channel_params.dust_limit_msat)
+        }
+        p if p.eq_ignore_ascii_case("max_htlc_value_in_flight_msat") => Ok(variables
+            .openinginfo
+            .channel_params
+            .max_htlc_value_in_flight_msat),
+        p if p.eq_ignore_ascii_case("channel_reserve_msat") => {
+            Ok(variables.openinginfo.channel_params.channel_reserve_msat)
+        }
+        p if p.eq_ignore_ascii_case("htlc_minimum_msat") => {
+            Ok(variables.openinginfo.channel_params.htlc_minimum_msat)
+        }
+        p if p.eq_ignore_ascii_case("feerate_per_kw") => {
+            Ok(variables.openinginfo.channel_params.feerate_per_kw)
+        }
+        p if p.eq_ignore_ascii_case("funding_feerate_per_kw") => {
+            Ok(variables.openinginfo.channel_params.funding_feerate_per_kw)
+        }
+        p if p.eq_ignore_ascii_case("to_self_delay") => {
+            Ok(variables.openinginfo.channel_params.to_self_delay)
+        }
+        p if p.eq_ignore_ascii_case("max_accepted_htlcs") => {
+            Ok(variables.openinginfo.channel_params.max_accepted_htlcs)
+        }
+        p if p.eq_ignore_ascii_case("locktime") => {
+            Ok(variables.openinginfo.channel_params.locktime)
+        }
+        p if p.eq_ignore_ascii_case("requested_lease_msat") => {
+            Ok(variables.openinginfo.channel_params.requested_lease_msat)
+        }
+        p if p.eq_ignore_ascii_case("chan_type_anchors") => Ok(u64::from(has_channel_type_bit(
+            &variables.openinginfo.channel_params.channel_type,
+            22,
+        ))),
+        p if p.eq_ignore_ascii_case("chan_type_scid_alias") => Ok(u64::from(has_channel_type_bit(
+            &variables.openinginfo.channel_params.channel_type,
+            46,
+        ))),
+        p if p.eq_ignore_ascii_case("chan_type_zeroconf") => Ok(u64::from(has_channel_type_bit(
+            &variables.openinginfo.channel_params.channel_type,
+            50,
+        ))),
+        p if p.eq_ignore_ascii_case("chan_type_taproot") => {
+            let channel_type = &variables.openinginfo.channel_params.channel_type;
+            // option_simple_taproot or its staging bit
+            Ok(u64::from(
+                has_channel_type_bit(channel_type, 80) || has_channel_type_bit(channel_type, 180),
+            ))
+        }
+        p if p.eq_ignore_ascii_case("ping") => Ok(u64::from(variables.ping.ok_or_else(unknown)?)),
+        p if p.eq_ignore_ascii_case("ping_median") => {
+            Ok(variables.ping_stats.as_ref().ok_or_else(unknown)?.median)
+        }
+        p if p.eq_ignore_ascii_case("ping_max") => {
+            Ok(variables.ping_stats.as_ref().ok_or_else(unknown)?.max)
+        }
+        p if p.eq_ignore_ascii_case("ping_jitter") => {
+            Ok(variables.ping_stats.as_ref().ok_or_else(unknown)?.jitter)
+        }
+        p if p.eq_ignore_ascii_case("ping_loss_pct") => {
+            Ok(variables.ping_stats.as_ref().ok_or_else(unknown)?.loss_pct)
+        }
+        p if p.eq_ignore_ascii_case("oneml_capacity") => Ok(variables
+            .oneml_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .capacity
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("oneml_channelcount") => Ok(variables
+            .oneml_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .channelcount
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("oneml_age") => Ok(variables
+            .oneml_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .age
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("oneml_growth") => Ok(variables
+            .oneml_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .growth
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("oneml_availability") => Ok(variables
+            .oneml_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .availability
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("amboss_capacity_rank") => {
+            if let Some(metrics) = &variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .graph_info
+                .metrics
+            {
+                Ok(metrics.capacity_rank)
+            } else {
+                Ok(u64::MAX)
+            }
+        }
+        p if p.eq_ignore_ascii_case("amboss_channels_rank") => {
+            if let Some(metrics) = &variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .graph_info
+                .metrics
+            {
+                Ok(metrics.channels_rank)
+            } else {
+                Ok(u64::MAX)
+            }
+        }
+        p if p.eq_ignore_ascii_case("amboss_has_email") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.email.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("amboss_has_linkedin") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.linkedin.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("amboss_has_nostr") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.nostr.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("amboss_has_telegram") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.telegram.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("amboss_has_twitter") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.twitter.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("amboss_has_website") => Ok(u64::from(
+            variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .info
+                .as_ref()
+                .is_some_and(|i| i.website.is_some()),
+        )),
+        p if p.eq_ignore_ascii_case("hist_forwards_30d") => {
+            Ok(variables.history.as_ref().ok_or_else(unknown)?.forwards_30d)
+        }
+        p if p.eq_ignore_ascii_case("hist_fee_earned_msat_30d") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .fee_earned_msat_30d),
+        p if p.eq_ignore_ascii_case("hist_volume_msat_30d") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .volume_msat_30d),
+        p if p.eq_ignore_ascii_case("hist_failed_forward_ratio") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .failed_forward_ratio),
+        p if p.eq_ignore_ascii_case("hist_closed_count") => {
+            Ok(variables.history.as_ref().ok_or_else(unknown)?.closed_count)
+        }
+        p if p.eq_ignore_ascii_case("hist_force_closed_by_peer") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .force_closed_by_peer),
+        p if p.eq_ignore_ascii_case("hist_mutual_closes") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .mutual_closes),
+        p if p.eq_ignore_ascii_case("hist_min_channel_lifetime_blocks") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .min_channel_lifetime_blocks),
+        p if p.eq_ignore_ascii_case("hist_last_close_age_blocks") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .last_close_age_blocks),
+        p if p.eq_ignore_ascii_case("hist_uptime_ratio_7d") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .uptime_ratio_7d),
+        p if p.eq_ignore_ascii_case("hist_disconnects_24h") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .disconnects_24h),
+        p if p.eq_ignore_ascii_case("hist_latency_p50_ms") => Ok(variables
+            .history
+            .as_ref()
+            .ok_or_else(unknown)?
+            .latency_p50_ms),
+        p if p.eq_ignore_ascii_case("conn_is_tor") => Ok(u64::from(
+            variables.connection.as_ref().ok_or_else(unknown)?.is_tor,
+        )),
+        p if p.eq_ignore_ascii_case("conn_ip_version") => Ok(variables
+            .connection
+            .as_ref()
+            .ok_or_else(unknown)?
+            .ip_version),
+        p if p.eq_ignore_ascii_case("conn_is_private_ip") => Ok(u64::from(
+            variables
+                .connection
+                .as_ref()
+                .ok_or_else(unknown)?
+                .is_private_ip,
+        )),
+        p if p.eq_ignore_ascii_case("cluster_size") => {
+            Ok(variables.cluster.as_ref().ok_or_else(unknown)?.size)
+        }
+        p if p.eq_ignore_ascii_case("cluster_channels_with_us") => Ok(variables
+            .cluster
+            .as_ref()
+            .ok_or_else(unknown)?
+            .channels_with_us),
+        p if p.eq_ignore_ascii_case("geo_asn") => {
+            Ok(variables.geo.as_ref().ok_or_else(unknown)?.asn)
+        }
+        p if p.eq_ignore_ascii_case("geo_asn_channel_count") => Ok(variables
+            .geo
+            .as_ref()
+            .ok_or_else(unknown)?
+            .asn_channel_count),
+        p if p.eq_ignore_ascii_case("self_channel_count") => Ok(variables
+            .own_node
+            .as_ref()
+            .ok_or_else(unknown)?
+            .channel_count),
+        p if p.eq_ignore_ascii_case("self_pending_opens") => Ok(variables
+            .own_node
+            .as_ref()
+            .ok_or_else(unknown)?
+            .pending_opens),
+        p if p.eq_ignore_ascii_case("self_onchain_sat") => {
+            Ok(variables.own_node.as_ref().ok_or_else(unknown)?.onchain_sat)
+        }
+        p if p.eq_ignore_ascii_case("self_inbound_ratio") => Ok(variables
+            .own_node
+            .as_ref()
+            .ok_or_else(unknown)?
+            .inbound_ratio),
+        p if p.eq_ignore_ascii_case("self_total_capacity_sat") => Ok(variables
+            .own_node
+            .as_ref()
+            .ok_or_else(unknown)?
+            .total_capacity_sat),
+        p if p.eq_ignore_ascii_case("mempool_first_seen") => Ok(variables
+            .mempool_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .first_seen
+            .unwrap_or(u64::MAX)),
+        p if p.eq_ignore_ascii_case("mempool_channel_count") => Ok(variables
+            .mempool_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .channel_count
+            .unwrap_or(0)),
+        p if p.eq_ignore_ascii_case("mempool_capacity") => Ok(variables
+            .mempool_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .capacity
+            .unwrap_or(0)),
+        p if p.eq_ignore_ascii_case("mempool_as_number") => Ok(variables
+            .mempool_data
+            .as_ref()
+            .ok_or_else(unknown)?
+            .as_number
+            .unwrap_or(0)),
+        p if p.eq_ignore_ascii_case("amboss_terminal_web_rank") => {
+            if let Some(term_web) = &variables
+                .amboss_data
+                .as_ref()
+                .ok_or_else(unknown)?
+                .get_node
+                .socials
+                .lightning_labs
+                .terminal_web
+            {
+                Ok(term_web.position)
+            } else {
+                Ok(u64::MAX)
+            }
+        }
+        _ => Err(anyhow!("Invalid variable name: {name}")),
+    }
+}
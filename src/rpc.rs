@@ -22,11 +22,21 @@ use tokio::{
 use crate::{
     OPT_BLOCK_MODE,
     PLUGIN_NAME,
-    collect::{collect_data, ln_ping},
-    config::{read_pubkey_list, read_zeroconf_list},
+    collect::{channels_with_us, collect_data, ln_ping},
+    config::{read_geo_db, read_pubkey_list, read_zeroconf_list},
     notify::notify,
     parser::{evaluate_rule, parse_rule},
-    structs::{BlockMode, ChannelFlags, ClnrodParser, NotifyVerbosity, PluginState},
+    structs::{
+        BlockMode,
+        Capability,
+        ChannelFlags,
+        ChannelParams,
+        ClnrodParser,
+        NotifyVerbosity,
+        PING_COUNT_MAX,
+        PluginState,
+    },
+    tasks::refresh_graph_cache,
 };
 
 pub async fn clnrod_reload(
@@ -50,8 +60,11 @@ pub async fn clnrod_reload(
     let (zero_removed, zero_added) =
         read_zeroconf_list(plugin.state().zero_conf_list.clone(), &plugin_dir).await?;
 
+    let geo_ranges = read_geo_db(plugin.state().geo_db.clone(), &plugin_dir).await?;
+
     Ok(json!({"removed":removed, "added":added,
-         "zeroconf_removed":zero_removed, "zeroconf_added":zero_added}))
+         "zeroconf_removed":zero_removed, "zeroconf_added":zero_added,
+         "geo_ranges":geo_ranges}))
 }
 
 pub async fn clnrod_testrule(
@@ -59,7 +72,7 @@ pub async fn clnrod_testrule(
     args: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     let config = plugin.state().config.lock().clone();
-    let (pubkey, public, their_funding_msat, rule) = match &args {
+    let (pubkey, public, their_funding_msat, rule, channel_params) = match &args {
         serde_json::Value::Object(o) => {
             let pubkey = if let Some(pk) = o.get("pubkey") {
                 PublicKey::from_str(pk.as_str().ok_or_else(|| anyhow!("bad pubkey string"))?)
@@ -91,7 +104,8 @@ pub async fn clnrod_testrule(
                     rule='x == 5' pubkey=XXXXX their_funding_sat=50000 public=true"
                 ));
             };
-            (pubkey, public, their_funding_msat, rule)
+            let channel_params = parse_channel_params(o.get("channel_params"))?;
+            (pubkey, public, their_funding_msat, rule, channel_params)
         }
         serde_json::Value::Array(a) => {
             let pubkey = if let Some(pk) = a.first() {
@@ -123,7 +137,8 @@ pub async fn clnrod_testrule(
                     rule='x == 5' pubkey=XXXXX their_funding_sat=50000 public=true"
                 ));
             };
-            (pubkey, public, their_funding_msat, rule)
+            let channel_params = parse_channel_params(a.get(4))?;
+            (pubkey, public, their_funding_msat, rule, channel_params)
         }
         _ => {
             return Err(anyhow!(
@@ -137,6 +152,7 @@ pub async fn clnrod_testrule(
         pubkey,
         Amount::from_msat(their_funding_msat),
         ChannelFlags { public },
+        channel_params,
         rule,
         config.ping_length,
     )
@@ -167,6 +183,14 @@ pub async fn clnrod_testrule(
     Ok(json!({"custom_rule_result":evaluate_result, "reject_reason":reject_reason}))
 }
 
+fn parse_channel_params(value: Option<&serde_json::Value>) -> Result<ChannelParams, Error> {
+    match value {
+        Some(v) => serde_json::from_value(v.clone())
+            .map_err(|e| anyhow!("channel_params: not a valid object: {e}")),
+        None => Ok(ChannelParams::default()),
+    }
+}
+
 pub async fn clnrod_testmail(
     plugin: Plugin<PluginState>,
     _args: serde_json::Value,
@@ -190,6 +214,11 @@ pub async fn clnrod_testping(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
+    plugin
+        .state()
+        .config
+        .lock()
+        .check_capability(Capability::Ping)?;
     let (pubkey_str, count, length) = match &args {
         serde_json::Value::Object(o) => {
             let pubkey_str = if let Some(pk) = o.get("pubkey") {
@@ -200,7 +229,7 @@ pub async fn clnrod_testping(
             let count = if let Some(pk) = o.get("count") {
                 pk.as_u64().ok_or_else(|| anyhow!("bad count number"))?
             } else {
-                3
+                plugin.state().config.lock().ping_count
             };
             let length = if let Some(pk) = o.get("length") {
                 u16::try_from(pk.as_u64().ok_or_else(|| anyhow!("bad length number"))?)?
@@ -225,7 +254,7 @@ pub async fn clnrod_testping(
             let count = if let Some(c) = a.get(1) {
                 c.as_u64().ok_or_else(|| anyhow!("bad count number"))?
             } else {
-                3
+                plugin.state().config.lock().ping_count
             };
             let length = if let Some(c) = a.get(2) {
                 u16::try_from(c.as_u64().ok_or_else(|| anyhow!("bad length number"))?)
@@ -246,6 +275,9 @@ pub async fn clnrod_testping(
     if count < 1 {
         return Err(anyhow!("count must be >=1"));
     }
+    if count > PING_COUNT_MAX {
+        return Err(anyhow!("count must be <={PING_COUNT_MAX}"));
+    }
     if length < 1 {
         return Err(anyhow!("length must be >=1"));
     }
@@ -262,13 +294,76 @@ pub async fn clnrod_testping(
     })
     .await?;
 
-    let pings = ln_ping(plugin, pubkey, count, length).await?;
-    let sum_pings = pings.iter().map(|y| u64::from(*y)).sum::<u64>();
-    let median = pings.get(pings.len() / 2).unwrap_or(&0);
-    Ok(json!({"min":pings.iter().min(),
-        "avg":sum_pings/(pings.len() as u64),
-        "median":median,
-        "max":pings.iter().max()}))
+    let ping_timeout_ms = plugin.state().config.lock().ping_timeout_ms;
+    let pings = ln_ping(plugin, pubkey, count, length, ping_timeout_ms).await?;
+    Ok(json!({"sent":pings.sent,
+        "received":pings.received,
+        "min":pings.min,
+        "avg":pings.average,
+        "median":pings.median,
+        "max":pings.max,
+        "jitter":pings.jitter,
+        "loss_pct":pings.loss_pct}))
+}
+
+pub async fn clnrod_listclusters(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let pubkey_value = match &args {
+        serde_json::Value::Object(o) => o.get("pubkey"),
+        serde_json::Value::Array(a) => {
+            if a.len() > 1 {
+                return Err(anyhow!(
+                    "Invalid amount of arguments! Only provide an optional pubkey."
+                ));
+            }
+            a.first()
+        }
+        _ => {
+            return Err(anyhow!(
+                "Invalid input! Use command like this: `lightning-cli clnrod-listclusters \
+            [pubkey=<pubkey>]` or this: `lightning-cli clnrod-listclusters [<pubkey>]`"
+            ));
+        }
+    };
+    let pubkey = match pubkey_value {
+        Some(pk) => Some(
+            PublicKey::from_str(pk.as_str().ok_or_else(|| anyhow!("bad pubkey string"))?)
+                .context("invalid pubkey")?,
+        ),
+        None => None,
+    };
+
+    if plugin.state().graph_cache.lock().age == 0 {
+        refresh_graph_cache(plugin.clone()).await?;
+    }
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let channel_counts = channels_with_us(&rpc_path).await?;
+
+    let graph_cache = plugin.state().graph_cache.lock();
+    let alias_cache = plugin.state().alias_cache.lock();
+    let clusters = graph_cache
+        .clusters
+        .iter()
+        .filter(|c| pubkey.is_none_or(|pk| c.members.contains(&pk)))
+        .map(|c| {
+            let channels_with_us = c
+                .members
+                .iter()
+                .filter_map(|m| channel_counts.get(m))
+                .sum::<u64>();
+            let members = c
+                .members
+                .iter()
+                .map(|m| json!({"id":m, "alias":alias_cache.get(m)}))
+                .collect::<Vec<_>>();
+            json!({"size":c.members.len(), "channels_with_us":channels_with_us,
+                "members":members, "signals":c.signals})
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({"clusters":clusters}))
 }
 
 pub async fn clnrod_managelists(
@@ -279,15 +374,11 @@ pub async fn clnrod_managelists(
     let pubkey = PublicKey::from_str(pubkey_str).context("invalid pubkey")?;
 
     match listtype_str {
-        "allow" => {
-            if plugin.state().config.lock().block_mode == BlockMode::Deny {
-                return Err(anyhow!("You are configured to use the denylist!"));
-            }
+        "allow" if plugin.state().config.lock().block_mode == BlockMode::Deny => {
+            return Err(anyhow!("You are configured to use the denylist!"));
         }
-        "deny" => {
-            if plugin.state().config.lock().block_mode == BlockMode::Allow {
-                return Err(anyhow!("You are configured to use the allowlist!"));
-            }
+        "deny" if plugin.state().config.lock().block_mode == BlockMode::Allow => {
+            return Err(anyhow!("You are configured to use the allowlist!"));
         }
         _ => (),
     }
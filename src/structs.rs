@@ -1,6 +1,7 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt::{self, Display, Formatter, Write as _},
+    net::IpAddr,
     str::FromStr,
     sync::Arc,
 };
@@ -13,6 +14,13 @@ use serde::{Deserialize, Serialize, de::IntoDeserializer};
 
 use crate::Rule;
 
+// Maximum number of external commands running at the same time
+const EXT_MAX_CONCURRENT: usize = 2;
+// Connects beyond this number of waiting prefetches are not prefetched
+const PREFETCH_MAX_QUEUED: usize = 16;
+// Pings run one after another, more would hold up the channel open too long
+pub const PING_COUNT_MAX: u64 = 20;
+
 #[derive(Clone)]
 pub struct PluginState {
     pub config: Arc<Mutex<Config>>,
@@ -20,8 +28,16 @@ pub struct PluginState {
     pub zero_conf_list: Arc<Mutex<HashSet<PublicKey>>>,
     pub amboss_lock: Arc<tokio::sync::Mutex<u128>>,
     pub oneml_lock: Arc<tokio::sync::Mutex<u128>>,
-    pub peerdata_cache: Arc<Mutex<HashMap<PublicKey, PeerDataCache>>>,
+    pub mempool_lock: Arc<tokio::sync::Mutex<u128>>,
+    pub peerdata_cache: Arc<Mutex<PeerCache>>,
     pub alias_cache: Arc<Mutex<HashMap<PublicKey, String>>>,
+    pub history_cache: Arc<Mutex<HistoryCache>>,
+    pub graph_cache: Arc<Mutex<GraphCache>>,
+    pub uptime_cache: Arc<Mutex<HashMap<PublicKey, PeerUptime>>>,
+    pub geo_db: Arc<Mutex<GeoDb>>,
+    pub ext_semaphore: Arc<tokio::sync::Semaphore>,
+    pub prefetch_lock: Arc<tokio::sync::Mutex<u128>>,
+    pub prefetch_semaphore: Arc<tokio::sync::Semaphore>,
 }
 impl PluginState {
     pub fn new() -> PluginState {
@@ -31,8 +47,16 @@ impl PluginState {
             zero_conf_list: Arc::new(Mutex::new(HashSet::new())),
             amboss_lock: Arc::new(tokio::sync::Mutex::new(0)),
             oneml_lock: Arc::new(tokio::sync::Mutex::new(0)),
-            peerdata_cache: Arc::new(Mutex::new(HashMap::new())),
+            mempool_lock: Arc::new(tokio::sync::Mutex::new(0)),
+            peerdata_cache: Arc::new(Mutex::new(PeerCache::default())),
             alias_cache: Arc::new(Mutex::new(HashMap::new())),
+            history_cache: Arc::new(Mutex::new(HistoryCache::default())),
+            graph_cache: Arc::new(Mutex::new(GraphCache::default())),
+            uptime_cache: Arc::new(Mutex::new(HashMap::new())),
+            geo_db: Arc::new(Mutex::new(GeoDb::default())),
+            ext_semaphore: Arc::new(tokio::sync::Semaphore::new(EXT_MAX_CONCURRENT)),
+            prefetch_lock: Arc::new(tokio::sync::Mutex::new(0)),
+            prefetch_semaphore: Arc::new(tokio::sync::Semaphore::new(PREFETCH_MAX_QUEUED)),
         }
     }
 }
@@ -61,6 +85,75 @@ impl Display for BlockMode {
     }
 }
 
+/// Version of the CLN node, e.g. `v25.09.1`. Suffixes like `rc1` or
+/// `-modded` are ignored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClnVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+impl FromStr for ClnVersion {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().trim_start_matches('v').split('.').map(|p| {
+            p.chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+                .parse::<u32>()
+        });
+        let major = parts.next().and_then(Result::ok);
+        let minor = parts.next().and_then(Result::ok);
+        let patch = parts.next().and_then(Result::ok).unwrap_or(0);
+        match (major, minor) {
+            (Some(major), Some(minor)) => Ok(ClnVersion {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(anyhow!("could not parse CLN version from {s}")),
+        }
+    }
+}
+impl Display for ClnVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.patch == 0 {
+            write!(f, "v{}.{:02}", self.major, self.minor)
+        } else {
+            write!(f, "v{}.{:02}.{}", self.major, self.minor, self.patch)
+        }
+    }
+}
+
+/// Features of CLN that clnrod only uses on versions where they are safe
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Ping,
+}
+impl Capability {
+    fn min_version(self) -> ClnVersion {
+        match self {
+            Capability::Ping => ClnVersion {
+                major: 25,
+                minor: 9,
+                patch: 0,
+            },
+        }
+    }
+    fn reason(self) -> &'static str {
+        match self {
+            Capability::Ping => "the ping command can get stuck and require a node restart",
+        }
+    }
+}
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Capability::Ping => write!(f, "ping"),
+        }
+    }
+}
+
 pub struct ClnrodParser {
     pub pratt_parser: PrattParser<Rule>,
 }
@@ -89,6 +182,23 @@ pub struct Config {
     pub send_mail: bool,
     pub notify_verbosity: NotifyVerbosity,
     pub ping_length: u16,
+    pub ping_count: u64,
+    pub ping_timeout_ms: u16,
+    pub mempool_url: String,
+    pub unknown_policy: UnknownPolicy,
+    pub ext_command: String,
+    pub ext_timeout_s: u64,
+    pub cln_version: Option<ClnVersion>,
+    pub cache_ttl_ping_s: u64,
+    pub cache_ttl_gossip_s: u64,
+    pub cache_ttl_reachability_s: u64,
+    pub cache_ttl_amboss_s: u64,
+    pub cache_ttl_oneml_s: u64,
+    pub cache_ttl_mempool_s: u64,
+    pub cache_ttl_ext_s: u64,
+    pub cache_ttl_error_s: u64,
+    pub cache_size: u64,
+    pub prefetch: bool,
 }
 impl Config {
     pub fn new() -> Config {
@@ -106,14 +216,280 @@ impl Config {
             send_mail: false,
             notify_verbosity: NotifyVerbosity::All,
             ping_length: 256,
+            ping_count: 3,
+            ping_timeout_ms: 5000,
+            mempool_url: String::new(),
+            unknown_policy: UnknownPolicy::Reject,
+            ext_command: String::new(),
+            ext_timeout_s: 5,
+            cln_version: None,
+            cache_ttl_ping_s: 10 * 60,
+            cache_ttl_gossip_s: 60 * 60,
+            cache_ttl_reachability_s: 60 * 60,
+            cache_ttl_amboss_s: 6 * 60 * 60,
+            cache_ttl_oneml_s: 6 * 60 * 60,
+            cache_ttl_mempool_s: 6 * 60 * 60,
+            cache_ttl_ext_s: 60 * 60,
+            cache_ttl_error_s: 5 * 60,
+            cache_size: 1000,
+            prefetch: true,
+        }
+    }
+
+    pub fn cache_ttl_s(&self, source: DataSource) -> u64 {
+        match source {
+            DataSource::Ping => self.cache_ttl_ping_s,
+            DataSource::Gossip => self.cache_ttl_gossip_s,
+            DataSource::Reachability => self.cache_ttl_reachability_s,
+            DataSource::Amboss => self.cache_ttl_amboss_s,
+            DataSource::Oneml => self.cache_ttl_oneml_s,
+            DataSource::Mempool => self.cache_ttl_mempool_s,
+            DataSource::Ext => self.cache_ttl_ext_s,
+        }
+    }
+
+    /// Errors if the CLN version is known and too old for `capability`
+    pub fn check_capability(&self, capability: Capability) -> Result<(), Error> {
+        match self.cln_version {
+            Some(version) if version < capability.min_version() => Err(anyhow!(
+                "{capability} is disabled on CLN {version}: {}. Requires CLN {} or newer",
+                capability.reason(),
+                capability.min_version()
+            )),
+            _ => Ok(()),
         }
     }
 }
 
-#[derive(Clone, Debug)]
+/// Content of the cache file, `version` must match `CACHE_FILE_VERSION`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CacheFile {
+    pub version: u64,
+    pub saved_at: u64,
+    // the cached data is only valid for the same sources
+    pub mempool_url: String,
+    pub ext_command: String,
+    pub peerdata: PeerCache,
+    pub aliases: HashMap<PublicKey, String>,
+}
+
+/// Lowercase names of the variables and functions a rule uses
+#[derive(Clone, Debug, Default)]
+pub struct RuleNames(pub HashSet<String>);
+impl RuleNames {
+    pub fn uses(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    pub fn uses_prefix(&self, prefix: &str) -> bool {
+        self.0.iter().any(|n| n.starts_with(prefix))
+    }
+}
+
+/// Sources of peer data that are cached, each with its own TTL
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DataSource {
+    Ping,
+    Gossip,
+    Reachability,
+    Amboss,
+    Oneml,
+    Mempool,
+    Ext,
+}
+impl DataSource {
+    pub const ALL: [DataSource; 7] = [
+        DataSource::Ping,
+        DataSource::Gossip,
+        DataSource::Reachability,
+        DataSource::Amboss,
+        DataSource::Oneml,
+        DataSource::Mempool,
+        DataSource::Ext,
+    ];
+
+    pub fn is_used_by(self, names: &RuleNames) -> bool {
+        match self {
+            DataSource::Ping => names.uses("ping") || names.uses_prefix("ping_"),
+            DataSource::Gossip => {
+                names.uses_prefix("cln_") || names.uses("feature") || names.uses("addr_in_cidr")
+            }
+            DataSource::Reachability => {
+                names.uses("cln_clearnet_reachable") || names.uses("cln_reachable_address_count")
+            }
+            DataSource::Amboss => names.uses_prefix("amboss_"),
+            DataSource::Oneml => names.uses_prefix("oneml_"),
+            DataSource::Mempool => names.uses_prefix("mempool_"),
+            DataSource::Ext => names.uses_prefix("ext_"),
+        }
+    }
+
+    pub fn copy(self, from: &PeerData, to: &mut PeerData) {
+        match self {
+            DataSource::Ping => {
+                to.ping = from.ping;
+                to.ping_stats.clone_from(&from.ping_stats);
+            }
+            DataSource::Gossip => to.peerinfo.clone_from(&from.peerinfo),
+            DataSource::Reachability => to.reachability.clone_from(&from.reachability),
+            DataSource::Amboss => to.amboss_data.clone_from(&from.amboss_data),
+            DataSource::Oneml => to.oneml_data = from.oneml_data,
+            DataSource::Mempool => to.mempool_data.clone_from(&from.mempool_data),
+            DataSource::Ext => to.ext_data.clone_from(&from.ext_data),
+        }
+    }
+}
+impl Display for DataSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DataSource::Ping => write!(f, "ping"),
+            DataSource::Gossip => write!(f, "gossip"),
+            DataSource::Reachability => write!(f, "reachability"),
+            DataSource::Amboss => write!(f, "amboss"),
+            DataSource::Oneml => write!(f, "1ml"),
+            DataSource::Mempool => write!(f, "mempool"),
+            DataSource::Ext => write!(f, "ext"),
+        }
+    }
+}
+
+/// Peer data cache with a maximum number of peers, the least recently used
+/// peer is evicted first
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerCache {
+    entries: HashMap<PublicKey, PeerDataCache>,
+}
+impl PeerCache {
+    pub fn get(&self, pubkey: &PublicKey) -> Option<&PeerDataCache> {
+        self.entries.get(pubkey)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Removes sources that are too old to be used and peers without any
+    /// sources left
+    pub fn prune(&mut self, config: &Config, unix_now_s: u64) {
+        for cache in self.entries.values_mut() {
+            cache.sources.retain(|source, cached| {
+                let ttl_s = if cached.error.is_some() {
+                    config.cache_ttl_error_s
+                } else {
+                    config.cache_ttl_s(*source)
+                };
+                unix_now_s.saturating_sub(cached.timestamp) < ttl_s
+            });
+        }
+        self.entries.retain(|_, c| !c.sources.is_empty());
+        self.evict(config.cache_size, None);
+    }
+
+    fn evict(&mut self, max_size: u64, keep: Option<PublicKey>) {
+        while self.entries.len() as u64 > max_size {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .filter(|(pk, _)| Some(**pk) != keep)
+                .min_by_key(|(_, c)| c.last_used)
+                .map(|(pk, _)| *pk)
+            else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    pub fn touch(&mut self, pubkey: &PublicKey, unix_now_s: u64) -> Option<PeerDataCache> {
+        let cache = self.entries.get_mut(pubkey)?;
+        cache.last_used = unix_now_s;
+        Some(cache.clone())
+    }
+
+    /// Stores the results of the fetched sources, `Some` being an error.
+    /// Data of the other sources is kept from the previous entry.
+    pub fn store(
+        &mut self,
+        pubkey: PublicKey,
+        peer_data: &PeerData,
+        fetched: &[(DataSource, Option<String>)],
+        unix_now_s: u64,
+        max_size: u64,
+    ) {
+        let cache = self.entries.entry(pubkey).or_insert_with(|| PeerDataCache {
+            peer_data: peer_data.clone(),
+            sources: HashMap::new(),
+            last_used: unix_now_s,
+        });
+        let mut merged = peer_data.clone();
+        for source in DataSource::ALL {
+            if !fetched.iter().any(|(s, e)| *s == source && e.is_none()) {
+                source.copy(&cache.peer_data, &mut merged);
+            }
+        }
+        cache.peer_data = merged;
+        for (source, error) in fetched {
+            cache.sources.insert(
+                *source,
+                SourceCache {
+                    timestamp: unix_now_s,
+                    error: error.clone(),
+                    openinginfo: (*source == DataSource::Ext)
+                        .then(|| serde_json::to_string(&peer_data.openinginfo).ok())
+                        .flatten(),
+                },
+            );
+        }
+        cache.last_used = unix_now_s;
+
+        self.evict(max_size, Some(pubkey));
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SourceCache {
+    pub timestamp: u64,
+    pub error: Option<String>,
+    // the ext command gets the channel open, so its results are only valid
+    // for the same `openinginfo`
+    #[serde(default)]
+    pub openinginfo: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PeerDataCache {
     pub peer_data: PeerData,
-    pub age: u64,
+    pub sources: HashMap<DataSource, SourceCache>,
+    pub last_used: u64,
+}
+impl PeerDataCache {
+    /// `Some(Ok(()))` if `peer_data` has fresh data from `source`,
+    /// `Some(Err(_))` if fetching it failed recently and `None` if it
+    /// has to be fetched
+    pub fn lookup(
+        &self,
+        source: DataSource,
+        config: &Config,
+        unix_now_s: u64,
+        openinginfo: &OpeningInfo,
+    ) -> Option<Result<(), String>> {
+        let cached = self.sources.get(&source)?;
+        if source == DataSource::Ext
+            && cached.openinginfo != serde_json::to_string(openinginfo).ok()
+        {
+            return None;
+        }
+        let age = unix_now_s.saturating_sub(cached.timestamp);
+        match &cached.error {
+            None if age < config.cache_ttl_s(source) => Some(Ok(())),
+            Some(e) if age < config.cache_ttl_error_s => Some(Err(e.clone())),
+            _ => None,
+        }
+    }
 }
 
 impl Display for PeerDataCache {
@@ -123,9 +499,38 @@ impl Display for PeerDataCache {
             self.peer_data.openinginfo.their_funding_sat,
             self.peer_data.openinginfo.channel_flags.public
         );
+        let params = &self.peer_data.openinginfo.channel_params;
+        write!(
+            result,
+            "\npush_msat: {}\ndust_limit_msat: {}\nmax_htlc_value_in_flight_msat: {}\
+            \nchannel_reserve_msat: {}\nhtlc_minimum_msat: {}\nfeerate_per_kw: {}\
+            \nfunding_feerate_per_kw: {}\nto_self_delay: {}\nmax_accepted_htlcs: {}\
+            \nlocktime: {}\nrequested_lease_msat: {}",
+            params.push_msat,
+            params.dust_limit_msat,
+            params.max_htlc_value_in_flight_msat,
+            params.channel_reserve_msat,
+            params.htlc_minimum_msat,
+            params.feerate_per_kw,
+            params.funding_feerate_per_kw,
+            params.to_self_delay,
+            params.max_accepted_htlcs,
+            params.locktime,
+            params.requested_lease_msat
+        )?;
+        write!(result, "\nchannel_type: {:?}", params.channel_type)?;
         if let Some(p) = self.peer_data.ping {
             write!(result, "\nping: {p}")?;
         }
+        if let Some(p) = &self.peer_data.ping_stats {
+            write!(result, "\nping_median: {}", p.median)?;
+            write!(result, "\nping_max: {}", p.max)?;
+            write!(result, "\nping_jitter: {}", p.jitter)?;
+            write!(result, "\nping_loss_pct: {}", p.loss_pct)?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.node_known {
+            write!(result, "\ncln_node_known: {c}")?;
+        }
         if let Some(c) = self.peer_data.peerinfo.node_capacity_sat {
             write!(result, "\ncln_node_capacity_sat: {c}")?;
         }
@@ -146,6 +551,63 @@ impl Display for PeerDataCache {
         if let Some(c) = self.peer_data.peerinfo.anchor_support {
             write!(result, "\ncln_anchor_support: {c}")?;
         }
+        if let Some(c) = self.peer_data.peerinfo.node_age_blocks {
+            write!(result, "\ncln_node_age_blocks: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.last_update_age_s {
+            write!(result, "\ncln_last_update_age_s: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.median_fee_ppm {
+            write!(result, "\ncln_median_fee_ppm: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.max_fee_ppm {
+            write!(result, "\ncln_max_fee_ppm: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.median_base_fee_msat {
+            write!(result, "\ncln_median_base_fee_msat: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.zero_base_fee_ratio {
+            write!(result, "\ncln_zero_base_fee_ratio: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.median_channel_sat {
+            write!(result, "\ncln_median_channel_sat: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.min_channel_sat {
+            write!(result, "\ncln_min_channel_sat: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.max_channel_sat {
+            write!(result, "\ncln_max_channel_sat: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.active_channel_ratio {
+            write!(result, "\ncln_active_channel_ratio: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.hops_from_us {
+            write!(result, "\ncln_hops_from_us: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.common_peers {
+            write!(result, "\ncln_common_peers: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.capacity_percentile {
+            write!(result, "\ncln_capacity_percentile: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.channels_percentile {
+            write!(result, "\ncln_channels_percentile: {c}")?;
+        }
+        if let Some(c) = self.peer_data.peerinfo.centrality_rank {
+            write!(result, "\ncln_centrality_rank: {c}")?;
+        }
+        if self.peer_data.peerinfo.node_known.is_some() {
+            match self.peer_data.peerinfo.impersonated_node {
+                Some(c) => write!(result, "\ncln_alias_impersonation: true ({c})")?,
+                None => result.push_str("\ncln_alias_impersonation: false"),
+            }
+        }
+        if let Some(c) = &self.peer_data.peerinfo.implementation {
+            write!(result, "\ncln_impl: {c}")?;
+        }
+        if let Some(c) = &self.peer_data.peerinfo.features {
+            write!(result, "\nfeatures: {c}")?;
+        }
 
         if let Some(oneml_data) = &self.peer_data.oneml_data {
             write!(
@@ -175,6 +637,138 @@ impl Display for PeerDataCache {
             )?;
         }
 
+        if let Some(history) = &self.peer_data.history {
+            write!(result, "\nhist_forwards_30d: {}", history.forwards_30d)?;
+            write!(
+                result,
+                "\nhist_fee_earned_msat_30d: {}",
+                history.fee_earned_msat_30d
+            )?;
+            write!(
+                result,
+                "\nhist_volume_msat_30d: {}",
+                history.volume_msat_30d
+            )?;
+            write!(
+                result,
+                "\nhist_failed_forward_ratio: {}",
+                history.failed_forward_ratio
+            )?;
+            write!(result, "\nhist_closed_count: {}", history.closed_count)?;
+            write!(
+                result,
+                "\nhist_force_closed_by_peer: {}",
+                history.force_closed_by_peer
+            )?;
+            write!(result, "\nhist_mutual_closes: {}", history.mutual_closes)?;
+            write!(
+                result,
+                "\nhist_min_channel_lifetime_blocks: {}",
+                history.min_channel_lifetime_blocks
+            )?;
+            write!(
+                result,
+                "\nhist_last_close_age_blocks: {}",
+                history.last_close_age_blocks
+            )?;
+            write!(
+                result,
+                "\nhist_uptime_ratio_7d: {}",
+                history.uptime_ratio_7d
+            )?;
+            write!(
+                result,
+                "\nhist_disconnects_24h: {}",
+                history.disconnects_24h
+            )?;
+            write!(result, "\nhist_latency_p50_ms: {}", history.latency_p50_ms)?;
+        }
+
+        if let Some(connection) = &self.peer_data.connection {
+            if let Some(ip) = connection.ip {
+                write!(result, "\nconnection address: {ip}")?;
+            }
+            write!(result, "\nconn_is_tor: {}", connection.is_tor)?;
+            write!(result, "\nconn_ip_version: {}", connection.ip_version)?;
+            write!(result, "\nconn_is_private_ip: {}", connection.is_private_ip)?;
+        }
+
+        if let Some(reachability) = &self.peer_data.reachability {
+            write!(
+                result,
+                "\ncln_clearnet_reachable: {}",
+                reachability.reachable
+            )?;
+            write!(
+                result,
+                "\ncln_reachable_address_count: {}",
+                reachability.address_count
+            )?;
+        }
+
+        if let Some(own_node) = &self.peer_data.own_node {
+            write!(result, "\nself_channel_count: {}", own_node.channel_count)?;
+            write!(result, "\nself_pending_opens: {}", own_node.pending_opens)?;
+            write!(result, "\nself_onchain_sat: {}", own_node.onchain_sat)?;
+            write!(result, "\nself_inbound_ratio: {}", own_node.inbound_ratio)?;
+            write!(
+                result,
+                "\nself_total_capacity_sat: {}",
+                own_node.total_capacity_sat
+            )?;
+        }
+
+        if let Some(cluster) = &self.peer_data.cluster {
+            write!(result, "\ncluster_size: {}", cluster.size)?;
+            write!(
+                result,
+                "\ncluster_channels_with_us: {}",
+                cluster.channels_with_us
+            )?;
+        }
+
+        if let Some(geo) = &self.peer_data.geo {
+            write!(result, "\ngeo_asn: {}", geo.asn)?;
+            write!(result, "\ngeo_country: {}", geo.country)?;
+            write!(result, "\ngeo_asn_channel_count: {}", geo.asn_channel_count)?;
+        }
+
+        if let Some(ext_data) = &self.peer_data.ext_data {
+            let mut ext_variables = ext_data.iter().collect::<Vec<_>>();
+            ext_variables.sort_unstable_by_key(|(name, _)| *name);
+            for (name, value) in ext_variables {
+                write!(result, "\n{name}: {value}")?;
+            }
+        }
+
+        if let Some(mempool_data) = &self.peer_data.mempool_data {
+            write!(
+                result,
+                "\nmempool_first_seen: {}",
+                mempool_data.first_seen.unwrap_or(u64::MAX)
+            )?;
+            write!(
+                result,
+                "\nmempool_channel_count: {}",
+                mempool_data.channel_count.unwrap_or(0)
+            )?;
+            write!(
+                result,
+                "\nmempool_capacity: {}",
+                mempool_data.capacity.unwrap_or(0)
+            )?;
+            write!(
+                result,
+                "\nmempool_country: {}",
+                mempool_data.iso_code.as_deref().unwrap_or("")
+            )?;
+            write!(
+                result,
+                "\nmempool_as_number: {}",
+                mempool_data.as_number.unwrap_or(0)
+            )?;
+        }
+
         if let Some(amboss_data) = &self.peer_data.amboss_data {
             if let Some(amboss_metrics) = &amboss_data.get_node.graph_info.metrics {
                 write!(
@@ -225,37 +819,378 @@ impl Display for PeerDataCache {
     }
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct HistoryCache {
+    pub peers: HashMap<PublicKey, PeerHistory>,
+    pub closes: HashMap<String, ChannelClose>,
+    // forwards per peer and day since the unix epoch, only new forwards are listed
+    pub forward_days: HashMap<PublicKey, BTreeMap<u64, ForwardStats>>,
+    // created_index of the next forward to list
+    pub forwards_index: u64,
+    // created_index of forwards that were not resolved yet and must be listed again
+    pub offered_forwards: BTreeSet<u64>,
+    pub age: u64,
+}
+
+// close of a channel from the bookkeeper and the close transaction
+#[derive(Clone, Debug)]
+pub struct ChannelClose {
+    pub height: Option<u32>,
+    pub kind: CloseKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseKind {
+    Mutual,
+    OurCommitment,
+    TheirCommitment,
+    Unknown,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ForwardStats {
+    pub settled: u64,
+    pub failed: u64,
+    pub fee_msat: u64,
+    pub volume_msat: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GraphCache {
+    pub neighbors: HashMap<PublicKey, HashSet<PublicKey>>,
+    pub capacities: HashMap<PublicKey, u64>,
+    pub ranks: HashMap<PublicKey, NodeRank>,
+    pub clusters: Vec<NodeCluster>,
+    pub node_clusters: HashMap<PublicKey, usize>,
+    pub node_ips: HashMap<PublicKey, Vec<IpAddr>>,
+    pub age: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NodeCluster {
+    pub members: Vec<PublicKey>,
+    pub signals: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NodeRank {
+    pub capacity_percentile: u64,
+    pub channels_percentile: u64,
+    pub centrality_rank: u64,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PeerData {
     pub ping: Option<u16>,
+    pub ping_stats: Option<PingStats>,
     pub peerinfo: PeerInfo,
     pub openinginfo: OpeningInfo,
     pub oneml_data: Option<OneMl>,
     pub amboss_data: Option<AmbossNodeData>,
+    pub mempool_data: Option<MempoolNode>,
+    pub history: Option<PeerHistory>,
+    pub own_node: Option<OwnNode>,
+    pub connection: Option<ConnectionInfo>,
+    pub geo: Option<GeoInfo>,
+    pub cluster: Option<ClusterInfo>,
+    pub reachability: Option<ReachabilityInfo>,
+    pub ext_data: Option<HashMap<String, RuleValue>>,
+    #[serde(skip)]
+    pub cidr_lists: HashMap<String, Vec<Cidr>>,
+}
+impl PeerData {
+    pub fn new(pubkey: PublicKey, openinginfo: OpeningInfo) -> PeerData {
+        let peerinfo = PeerInfo {
+            pubkey,
+            node_known: None,
+            channel_count: None,
+            node_capacity_sat: None,
+            has_clearnet: None,
+            has_tor: None,
+            anchor_support: None,
+            node_age_blocks: None,
+            last_update_age_s: None,
+            median_fee_ppm: None,
+            max_fee_ppm: None,
+            median_base_fee_msat: None,
+            zero_base_fee_ratio: None,
+            median_channel_sat: None,
+            min_channel_sat: None,
+            max_channel_sat: None,
+            active_channel_ratio: None,
+            hops_from_us: None,
+            common_peers: None,
+            capacity_percentile: None,
+            channels_percentile: None,
+            centrality_rank: None,
+            impersonated_node: None,
+            implementation: None,
+            features: None,
+            addresses: None,
+        };
+        PeerData {
+            ping: None,
+            ping_stats: None,
+            peerinfo,
+            openinginfo,
+            oneml_data: None,
+            amboss_data: None,
+            mempool_data: None,
+            history: None,
+            own_node: None,
+            connection: None,
+            geo: None,
+            cluster: None,
+            reachability: None,
+            ext_data: None,
+            cidr_lists: HashMap::new(),
+        }
+    }
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub ip: Option<IpAddr>,
+    pub is_tor: bool,
+    pub ip_version: u64,
+    pub is_private_ip: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cidr {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+}
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.trim().split_once('/') {
+            Some((a, p)) => (a, Some(p)),
+            None => (s.trim(), None),
+        };
+        let network = IpAddr::from_str(address)
+            .map_err(|e| anyhow!("Invalid CIDR `{s}`: {e}"))?
+            .to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .map_err(|e| anyhow!("Invalid CIDR `{s}`: {e}"))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(anyhow!("Invalid CIDR `{s}`: prefix length too big"));
+        }
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (network, ip, prefix_len) = match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (
+                u128::from(n.to_bits()) << 96,
+                u128::from(i.to_bits()) << 96,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (n.to_bits(), i.to_bits(), self.prefix_len),
+            _ => return false,
+        };
+        if prefix_len == 0 {
+            return true;
+        }
+        let mask = u128::MAX << (128 - u32::from(prefix_len));
+        network & mask == ip & mask
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PingStats {
+    pub sent: u64,
+    pub received: u64,
+    pub average: u64,
+    pub min: u64,
+    pub median: u64,
+    pub max: u64,
+    pub jitter: u64,
+    pub loss_pct: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ClusterInfo {
+    pub size: u64,
+    pub channels_with_us: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReachabilityInfo {
+    pub reachable: bool,
+    pub address_count: u64,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GeoInfo {
+    pub asn: u64,
+    pub country: String,
+    pub asn_channel_count: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GeoDb {
+    pub ranges: Vec<GeoRange>,
+}
+impl GeoDb {
+    pub fn lookup(&self, ip: &IpAddr) -> Option<&GeoRange> {
+        let ip = geo_ip_bits(ip);
+        let index = self.ranges.partition_point(|r| r.start <= ip);
+        let range = self.ranges.get(index.checked_sub(1)?)?;
+        if ip <= range.end { Some(range) } else { None }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GeoRange {
+    pub start: u128,
+    pub end: u128,
+    pub asn: u64,
+    pub country: String,
+}
+
+pub fn geo_ip_bits(ip: &IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped().to_bits(),
+        IpAddr::V6(v6) => v6.to_bits(),
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OwnNode {
+    pub channel_count: u64,
+    pub pending_opens: u64,
+    pub onchain_sat: u64,
+    pub inbound_ratio: u64,
+    pub total_capacity_sat: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerHistory {
+    pub forwards_30d: u64,
+    pub fee_earned_msat_30d: u64,
+    pub volume_msat_30d: u64,
+    pub failed_forward_ratio: u64,
+    pub closed_count: u64,
+    pub force_closed_by_peer: u64,
+    pub mutual_closes: u64,
+    pub min_channel_lifetime_blocks: u64,
+    pub last_close_age_blocks: u64,
+    pub uptime_ratio_7d: u64,
+    pub disconnects_24h: u64,
+    pub latency_p50_ms: u64,
+}
+impl Default for PeerHistory {
+    fn default() -> Self {
+        PeerHistory {
+            forwards_30d: 0,
+            fee_earned_msat_30d: 0,
+            volume_msat_30d: 0,
+            failed_forward_ratio: 0,
+            closed_count: 0,
+            force_closed_by_peer: 0,
+            mutual_closes: 0,
+            min_channel_lifetime_blocks: u64::MAX,
+            last_close_age_blocks: u64::MAX,
+            uptime_ratio_7d: u64::MAX,
+            disconnects_24h: 0,
+            latency_p50_ms: u64::MAX,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerUptime {
+    pub connected: bool,
+    pub hours: Vec<UptimeHour>,
+    pub disconnects: Vec<u64>,
+    pub latencies: Vec<PingSample>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UptimeHour {
+    pub hour: u64,
+    pub online: u64,
+    pub total: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PingSample {
+    pub timestamp: u64,
+    pub ms: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub pubkey: PublicKey,
+    pub node_known: Option<bool>,
     pub channel_count: Option<u64>,
     pub node_capacity_sat: Option<u64>,
     pub has_clearnet: Option<bool>,
     pub has_tor: Option<bool>,
     pub anchor_support: Option<bool>,
+    pub node_age_blocks: Option<u64>,
+    pub last_update_age_s: Option<u64>,
+    pub median_fee_ppm: Option<u64>,
+    pub max_fee_ppm: Option<u64>,
+    pub median_base_fee_msat: Option<u64>,
+    pub zero_base_fee_ratio: Option<u64>,
+    pub median_channel_sat: Option<u64>,
+    pub min_channel_sat: Option<u64>,
+    pub max_channel_sat: Option<u64>,
+    pub active_channel_ratio: Option<u64>,
+    pub hops_from_us: Option<u64>,
+    pub common_peers: Option<u64>,
+    pub capacity_percentile: Option<u64>,
+    pub channels_percentile: Option<u64>,
+    pub centrality_rank: Option<u64>,
+    pub impersonated_node: Option<PublicKey>,
+    pub implementation: Option<String>,
+    pub features: Option<String>,
+    pub addresses: Option<Vec<IpAddr>>,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct OpeningInfo {
     pub their_funding_sat: u64,
     pub multi_channel_count: u64,
     pub channel_flags: ChannelFlags,
+    pub channel_params: ChannelParams,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
 pub struct ChannelFlags {
     pub public: bool,
 }
 
+/// Parameters the peer proposed for the channel. Values that only exist
+/// for one version of the channel opening protocol are `0` for the other.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelParams {
+    pub push_msat: u64,
+    pub dust_limit_msat: u64,
+    pub max_htlc_value_in_flight_msat: u64,
+    pub channel_reserve_msat: u64,
+    pub htlc_minimum_msat: u64,
+    pub feerate_per_kw: u64,
+    pub funding_feerate_per_kw: u64,
+    pub to_self_delay: u64,
+    pub max_accepted_htlcs: u64,
+    pub locktime: u64,
+    pub requested_lease_msat: u64,
+    pub channel_type: Vec<u32>,
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct OneMl {
     pub capacity: Option<u64>,
@@ -265,6 +1200,17 @@ pub struct OneMl {
     pub availability: Option<u64>,
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MempoolNode {
+    pub first_seen: Option<u64>,
+    #[serde(rename = "active_channel_count")]
+    pub channel_count: Option<u64>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub capacity: Option<u64>,
+    pub iso_code: Option<String>,
+    pub as_number: Option<u64>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AmbossResponse {
     pub data: AmbossNodeData,
@@ -349,6 +1295,33 @@ pub struct AmbossExtensionsCostThrottleStatus {
     pub restore_rate: u64,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnknownPolicy {
+    Reject,
+    Accept,
+    Evaluate,
+}
+impl FromStr for UnknownPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(UnknownPolicy::Reject),
+            "accept" => Ok(UnknownPolicy::Accept),
+            "evaluate" => Ok(UnknownPolicy::Evaluate),
+            _ => Err(anyhow!("could not parse UnknownPolicy from {s}")),
+        }
+    }
+}
+impl Display for UnknownPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            UnknownPolicy::Reject => write!(f, "reject"),
+            UnknownPolicy::Accept => write!(f, "accept"),
+            UnknownPolicy::Evaluate => write!(f, "evaluate"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialOrd, PartialEq)]
 pub enum NotifyVerbosity {
     Error,
@@ -376,6 +1349,35 @@ impl Display for NotifyVerbosity {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RuleValue {
+    Integer(u64),
+    Text(String),
+}
+impl Display for RuleValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleValue::Integer(i) => write!(f, "{i}"),
+            RuleValue::Text(t) => write!(f, "\"{t}\""),
+        }
+    }
+}
+
+fn string_or_number<'de, D>(de: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<serde_json::Value>::deserialize(de)? {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::Number(n)) => Ok(n.as_u64()),
+        Some(serde_json::Value::String(s)) => s.parse().map(Some).map_err(serde::de::Error::custom),
+        Some(other) => Err(serde::de::Error::custom(format!(
+            "expected number or string, got: {other}"
+        ))),
+    }
+}
+
 fn empty_string_as_none<'de, D, T>(de: D) -> Result<Option<T>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -1,9 +1,82 @@
-use std::{path::Path, time::Instant};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use cln_plugin::Plugin;
-use cln_rpc::{model::requests::ListnodesRequest, ClnRpc};
+use cln_rpc::{
+    ClnRpc,
+    model::{
+        requests::{
+            BkprlistaccounteventsRequest,
+            GetinfoRequest,
+            ListchannelsRequest,
+            ListclosedchannelsRequest,
+            ListforwardsIndex,
+            ListforwardsRequest,
+            ListnodesRequest,
+            ListpeerchannelsRequest,
+            ListpeersRequest,
+            ListtransactionsRequest,
+            PingRequest,
+        },
+        responses::{
+            ListforwardsForwardsStatus,
+            ListnodesNodes,
+            ListnodesNodesAddressesType,
+            ListtransactionsTransactions,
+        },
+    },
+    primitives::{PublicKey, Sha256, ShortChannelId},
+};
+use tokio::{fs, sync::Semaphore, task::JoinSet, time::timeout};
 
-use crate::structs::PluginState;
+use crate::{
+    PLUGIN_NAME,
+    collect::{is_active_or_opening, is_private_ip, median, node_ips},
+    structs::{
+        CacheFile,
+        Capability,
+        ChannelClose,
+        CloseKind,
+        NodeCluster,
+        NodeRank,
+        PeerHistory,
+        PeerUptime,
+        PingSample,
+        PluginState,
+        UptimeHour,
+    },
+};
+
+const HISTORY_WINDOW_S: u64 = 30 * 24 * 60 * 60;
+const DAY_S: u64 = 24 * 60 * 60;
+const FORWARDS_PAGE_SIZE: u32 = 10_000;
+const CENTRALITY_SAMPLES: usize = 256;
+const UPTIME_WINDOW_S: u64 = 7 * 24 * 60 * 60;
+const DISCONNECT_WINDOW_S: u64 = 24 * 60 * 60;
+const UPTIME_PING_TIMEOUT_MS: u64 = 5000;
+const UPTIME_PING_CONCURRENCY: usize = 8;
+// Pings still running after this are dropped until the next hour
+const UPTIME_PING_DEADLINE_MS: u64 = 60_000;
+// Signals shared by more nodes are too generic to mean a common operator,
+// e.g. addresses of hosting providers
+const CLUSTER_MAX_SIGNAL_NODES: usize = 64;
+// Funding transactions with more funders are most likely batches of a
+// liquidity marketplace
+const CLUSTER_MAX_BATCH_FUNDERS: usize = 8;
+const CLUSTER_MIN_ALIAS_PATTERN_LEN: usize = 5;
+pub const UPTIME_FILE: &str = "uptime.json";
+pub const CACHE_FILE: &str = "cache.json";
+// Increase on incompatible changes of `CacheFile`, older files are ignored
+pub const CACHE_FILE_VERSION: u64 = 2;
+
+// Endpoints of the channels of each funding transaction (block, txindex)
+type FundingTxs = HashMap<(u32, u32), HashMap<ShortChannelId, (PublicKey, PublicKey)>>;
 
 pub async fn refresh_alias_cache(plugin: Plugin<PluginState>) -> Result<(), anyhow::Error> {
     let now = Instant::now();
@@ -28,3 +101,834 @@ pub async fn refresh_alias_cache(plugin: Plugin<PluginState>) -> Result<(), anyh
     );
     Ok(())
 }
+
+pub async fn refresh_graph_cache(plugin: Plugin<PluginState>) -> Result<(), anyhow::Error> {
+    let now = Instant::now();
+    log::debug!("Starting refresh_graph_cache task");
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let mut rpc = ClnRpc::new(&rpc_path).await?;
+
+    let listchannels = rpc
+        .call_typed(&ListchannelsRequest {
+            short_channel_id: None,
+            source: None,
+            destination: None,
+        })
+        .await?
+        .channels;
+    let listnodes = rpc.call_typed(&ListnodesRequest { id: None }).await?.nodes;
+    let node_ips: HashMap<PublicKey, Vec<IpAddr>> = listnodes
+        .iter()
+        .map(|n| (n.nodeid, node_ips(n)))
+        .filter(|(_, ips)| !ips.is_empty())
+        .collect();
+    let mut neighbors: HashMap<PublicKey, HashSet<PublicKey>> = HashMap::new();
+    let mut capacities: HashMap<PublicKey, u64> = HashMap::new();
+    let mut channel_counts: HashMap<PublicKey, u64> = HashMap::new();
+    let mut funding_txs: FundingTxs = HashMap::new();
+    for chan in listchannels {
+        funding_txs
+            .entry((
+                chan.short_channel_id.block(),
+                chan.short_channel_id.txindex(),
+            ))
+            .or_default()
+            .insert(
+                chan.short_channel_id,
+                (
+                    chan.source.min(chan.destination),
+                    chan.source.max(chan.destination),
+                ),
+            );
+        neighbors
+            .entry(chan.source)
+            .or_default()
+            .insert(chan.destination);
+        neighbors
+            .entry(chan.destination)
+            .or_default()
+            .insert(chan.source);
+        *capacities.entry(chan.source).or_default() += chan.amount_msat.msat() / 1000;
+        *channel_counts.entry(chan.source).or_default() += 1;
+    }
+
+    let (neighbors, capacities, ranks, clusters) = tokio::task::spawn_blocking(move || {
+        let ranks = compute_node_ranks(&neighbors, &capacities, &channel_counts);
+        let clusters = compute_clusters(&listnodes, &funding_txs, &channel_counts);
+        (neighbors, capacities, ranks, clusters)
+    })
+    .await?;
+
+    let mut graph_cache = plugin.state().graph_cache.lock();
+    graph_cache.neighbors = neighbors;
+    graph_cache.capacities = capacities;
+    graph_cache.ranks = ranks;
+    graph_cache.node_clusters = clusters
+        .iter()
+        .enumerate()
+        .flat_map(|(i, c)| c.members.iter().map(move |m| (*m, i)))
+        .collect();
+    graph_cache.clusters = clusters;
+    graph_cache.node_ips = node_ips;
+    graph_cache.age = unix_now_s;
+
+    log::debug!(
+        "refresh_graph_cache done in: {}ms",
+        now.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+fn compute_node_ranks(
+    neighbors: &HashMap<PublicKey, HashSet<PublicKey>>,
+    capacities: &HashMap<PublicKey, u64>,
+    channel_counts: &HashMap<PublicKey, u64>,
+) -> HashMap<PublicKey, NodeRank> {
+    let nodes = neighbors.keys().copied().collect::<Vec<_>>();
+    let index = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (*n, i))
+        .collect::<HashMap<_, _>>();
+    let adjacency = nodes
+        .iter()
+        .map(|n| neighbors[n].iter().map(|m| index[m]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let centrality = betweenness_centrality(&adjacency);
+
+    let mut by_centrality = (0..nodes.len()).collect::<Vec<_>>();
+    by_centrality.sort_unstable_by(|a, b| centrality[*b].total_cmp(&centrality[*a]));
+    let mut centrality_ranks = vec![0; nodes.len()];
+    for (rank, i) in by_centrality.into_iter().enumerate() {
+        centrality_ranks[i] = rank as u64 + 1;
+    }
+
+    let mut sorted_capacities = nodes
+        .iter()
+        .map(|n| capacities.get(n).copied().unwrap_or(0))
+        .collect::<Vec<_>>();
+    sorted_capacities.sort_unstable();
+    let mut sorted_channel_counts = nodes
+        .iter()
+        .map(|n| channel_counts.get(n).copied().unwrap_or(0))
+        .collect::<Vec<_>>();
+    sorted_channel_counts.sort_unstable();
+
+    nodes
+        .iter()
+        .enumerate()
+        .map(|(i, n)| {
+            let capacity = capacities.get(n).copied().unwrap_or(0);
+            let channel_count = channel_counts.get(n).copied().unwrap_or(0);
+            (
+                *n,
+                NodeRank {
+                    capacity_percentile: percentile(&sorted_capacities, capacity),
+                    channels_percentile: percentile(&sorted_channel_counts, channel_count),
+                    centrality_rank: centrality_ranks[i],
+                },
+            )
+        })
+        .collect()
+}
+
+// Percentage of values that are lower than `value`
+fn percentile(sorted: &[u64], value: u64) -> u64 {
+    (sorted.partition_point(|v| *v < value) as u64 * 100)
+        .checked_div(sorted.len() as u64)
+        .unwrap_or(0)
+}
+
+// Brandes' algorithm with BFS from a sample of evenly spread source nodes,
+// running it from every node takes too long on mainnet
+fn betweenness_centrality(adjacency: &[Vec<usize>]) -> Vec<f64> {
+    let node_count = adjacency.len();
+    let mut centrality = vec![0.0; node_count];
+    if node_count == 0 {
+        return centrality;
+    }
+    let step = node_count.div_ceil(CENTRALITY_SAMPLES);
+
+    for source in (0..node_count).step_by(step) {
+        let mut stack = Vec::with_capacity(node_count);
+        let mut predecessors: Vec<Vec<usize>> = vec![Vec::new(); node_count];
+        let mut paths = vec![0.0; node_count];
+        let mut distance: Vec<Option<u64>> = vec![None; node_count];
+        paths[source] = 1.0;
+        distance[source] = Some(0);
+
+        let mut queue = VecDeque::from([source]);
+        while let Some(v) = queue.pop_front() {
+            stack.push(v);
+            let next_distance = distance[v].unwrap() + 1;
+            for &w in &adjacency[v] {
+                if distance[w].is_none() {
+                    distance[w] = Some(next_distance);
+                    queue.push_back(w);
+                }
+                if distance[w] == Some(next_distance) {
+                    paths[w] += paths[v];
+                    predecessors[w].push(v);
+                }
+            }
+        }
+
+        let mut dependency = vec![0.0; node_count];
+        while let Some(w) = stack.pop() {
+            for &v in &predecessors[w] {
+                dependency[v] += paths[v] / paths[w] * (1.0 + dependency[w]);
+            }
+            if w != source {
+                centrality[w] += dependency[w];
+            }
+        }
+    }
+    centrality
+}
+
+// Groups nodes that likely belong to the same operator by signals in the
+// gossip: shared announced hosts, numbered aliases like `node-1`/`node-2` and
+// channels funded in the same transaction
+fn compute_clusters(
+    nodes: &[ListnodesNodes],
+    funding_txs: &FundingTxs,
+    channel_counts: &HashMap<PublicKey, u64>,
+) -> Vec<NodeCluster> {
+    let mut signals: HashMap<String, HashSet<PublicKey>> = HashMap::new();
+    for node in nodes {
+        for address in node.addresses.iter().flatten() {
+            let Some(host) = &address.address else {
+                continue;
+            };
+            let shared = match address.item_type {
+                ListnodesNodesAddressesType::IPV4 | ListnodesNodesAddressesType::IPV6 => host
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| !is_private_ip(&ip.to_canonical())),
+                ListnodesNodesAddressesType::DNS | ListnodesNodesAddressesType::TORV3 => true,
+                _ => false,
+            };
+            if shared {
+                signals
+                    .entry(format!("address {host}"))
+                    .or_default()
+                    .insert(node.nodeid);
+            }
+        }
+        if let Some(pattern) = node.alias.as_deref().and_then(alias_pattern) {
+            signals
+                .entry(format!("alias {pattern}"))
+                .or_default()
+                .insert(node.nodeid);
+        }
+    }
+    for ((block, txindex), channels) in funding_txs {
+        if channels.len() < 2 {
+            continue;
+        }
+        // a node that is part of every channel funded them all by itself
+        let mut endpoints = channels.values();
+        let (first_a, first_b) = endpoints.next().unwrap();
+        if endpoints.clone().all(|(a, b)| a == first_a || b == first_a)
+            || endpoints.all(|(a, b)| a == first_b || b == first_b)
+        {
+            continue;
+        }
+        // otherwise guess the smaller node of each channel as its funder
+        let funders = channels
+            .values()
+            .filter_map(|(a, b)| {
+                let count_a = channel_counts.get(a).copied().unwrap_or(0);
+                let count_b = channel_counts.get(b).copied().unwrap_or(0);
+                match count_a.cmp(&count_b) {
+                    Ordering::Less => Some(*a),
+                    Ordering::Greater => Some(*b),
+                    Ordering::Equal => None,
+                }
+            })
+            .collect::<HashSet<_>>();
+        if funders.len() <= CLUSTER_MAX_BATCH_FUNDERS {
+            signals.insert(format!("funding tx {block}x{txindex}"), funders);
+        }
+    }
+
+    let mut index: HashMap<PublicKey, usize> = HashMap::new();
+    let mut parents: Vec<usize> = Vec::new();
+    let mut used_signals = Vec::new();
+    for (signal, members) in signals {
+        if members.len() < 2 || members.len() > CLUSTER_MAX_SIGNAL_NODES {
+            continue;
+        }
+        let members = members
+            .into_iter()
+            .map(|m| {
+                *index.entry(m).or_insert_with(|| {
+                    parents.push(parents.len());
+                    parents.len() - 1
+                })
+            })
+            .collect::<Vec<_>>();
+        for member in &members[1..] {
+            let root_a = find_root(&mut parents, members[0]);
+            let root_b = find_root(&mut parents, *member);
+            parents[root_b] = root_a;
+        }
+        used_signals.push((signal, members[0]));
+    }
+
+    let mut clusters: HashMap<usize, NodeCluster> = HashMap::new();
+    for (node, i) in index {
+        let root = find_root(&mut parents, i);
+        clusters.entry(root).or_default().members.push(node);
+    }
+    for (signal, member) in used_signals {
+        let root = find_root(&mut parents, member);
+        clusters.get_mut(&root).unwrap().signals.push(signal);
+    }
+    let mut clusters = clusters.into_values().collect::<Vec<_>>();
+    for cluster in &mut clusters {
+        cluster.members.sort_unstable();
+        cluster.signals.sort_unstable();
+    }
+    clusters.sort_unstable_by(|a, b| {
+        b.members
+            .len()
+            .cmp(&a.members.len())
+            .then_with(|| a.members.cmp(&b.members))
+    });
+    clusters
+}
+
+fn find_root(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+// Letters of a numbered alias, e.g. `lnhub` for `LN-Hub 02`
+fn alias_pattern(alias: &str) -> Option<String> {
+    if !alias.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let pattern = alias
+        .chars()
+        .filter(|c| c.is_alphabetic())
+        .flat_map(char::to_lowercase)
+        .collect::<String>();
+    if pattern.chars().count() < CLUSTER_MIN_ALIAS_PATTERN_LEN {
+        return None;
+    }
+    Some(pattern)
+}
+
+#[derive(Default)]
+struct CloseStats {
+    closed: u64,
+    force_closed_by_peer: u64,
+    mutual: u64,
+    min_lifetime_blocks: Option<u64>,
+    last_close_height: Option<u32>,
+}
+
+// BOLT #3: commitment transactions have 0x20 in the upper byte of the
+// locktime and 0x80 in the upper byte of the sequence, closing transactions
+// never do. Only our own commitment has our last_commitment_txid
+fn close_kind(
+    tx: &ListtransactionsTransactions,
+    last_commitment_txid: Option<&Sha256>,
+) -> CloseKind {
+    let commitment =
+        tx.locktime >> 24 == 0x20 && tx.inputs.first().is_some_and(|i| i.sequence >> 24 == 0x80);
+    if !commitment {
+        return CloseKind::Mutual;
+    }
+    match last_commitment_txid {
+        Some(txid) if txid.to_string().eq(&tx.hash) => CloseKind::OurCommitment,
+        Some(_) => CloseKind::TheirCommitment,
+        None => CloseKind::Unknown,
+    }
+}
+
+pub async fn refresh_history_cache(plugin: Plugin<PluginState>) -> Result<(), anyhow::Error> {
+    let now = Instant::now();
+    log::debug!("Starting refresh_history_cache task");
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    let blockheight = rpc.call_typed(&GetinfoRequest {}).await?.blockheight;
+    let mut closes = plugin.state().history_cache.lock().closes.clone();
+    let mut bkpr_available = true;
+    let mut transactions: Option<Vec<ListtransactionsTransactions>> = None;
+
+    let mut scid_peers: HashMap<ShortChannelId, PublicKey> = HashMap::new();
+    let peer_channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            short_channel_id: None,
+            channel_id: None,
+        })
+        .await?
+        .channels;
+    for chan in peer_channels {
+        let aliases = chan.alias.map_or(vec![], |a| vec![a.local, a.remote]);
+        for scid in aliases.into_iter().chain([chan.short_channel_id]).flatten() {
+            scid_peers.insert(scid, chan.peer_id);
+        }
+    }
+    let closed_channels = rpc
+        .call_typed(&ListclosedchannelsRequest { id: None })
+        .await?
+        .closedchannels;
+    let mut close_stats: HashMap<PublicKey, CloseStats> = HashMap::new();
+    for chan in closed_channels {
+        let Some(peer_id) = chan.peer_id else {
+            continue;
+        };
+        let aliases = chan.alias.map_or(vec![], |a| vec![a.local, a.remote]);
+        for scid in aliases.into_iter().chain([chan.short_channel_id]).flatten() {
+            scid_peers.insert(scid, peer_id);
+        }
+
+        let channel_id = chan.channel_id.to_string();
+        if !closes.contains_key(&channel_id) {
+            let mut close_height = None;
+            if bkpr_available {
+                match rpc
+                    .call_typed(&BkprlistaccounteventsRequest {
+                        account: Some(channel_id.clone()),
+                        payment_id: None,
+                    })
+                    .await
+                {
+                    Ok(events) => {
+                        close_height = events
+                            .events
+                            .into_iter()
+                            .find(|e| e.tag.eq("channel_close"))
+                            .and_then(|e| e.blockheight);
+                    }
+                    Err(e) => {
+                        log::info!(
+                            "Could not get channel closes from bookkeeper, \
+                            some hist_ variables will be unknown: {e}"
+                        );
+                        bkpr_available = false;
+                    }
+                }
+            }
+            if transactions.is_none() {
+                transactions = Some(
+                    rpc.call_typed(&ListtransactionsRequest {})
+                        .await?
+                        .transactions,
+                );
+            }
+            let close_tx = transactions.iter().flatten().find(|tx| {
+                tx.inputs
+                    .iter()
+                    .any(|i| i.txid.eq(&chan.funding_txid) && i.index == chan.funding_outnum)
+            });
+            // closed channels are only listed once they are resolved, so
+            // what is unknown now stays unknown and is not looked up again
+            closes.insert(
+                channel_id.clone(),
+                ChannelClose {
+                    height: close_height.or(close_tx.map(|tx| tx.blockheight).filter(|h| *h > 0)),
+                    kind: close_tx.map_or(CloseKind::Unknown, |tx| {
+                        close_kind(tx, chan.last_commitment_txid.as_ref())
+                    }),
+                },
+            );
+        }
+        let channel_close = closes.get(&channel_id);
+
+        let stats = close_stats.entry(peer_id).or_default();
+        stats.closed += 1;
+        match channel_close.map(|c| c.kind) {
+            Some(CloseKind::TheirCommitment) => stats.force_closed_by_peer += 1,
+            Some(CloseKind::Mutual) => stats.mutual += 1,
+            _ => (),
+        }
+
+        if let Some(close_height) = channel_close.and_then(|c| c.height) {
+            if let Some(scid) = chan.short_channel_id {
+                let lifetime = u64::from(close_height.saturating_sub(scid.block()));
+                stats.min_lifetime_blocks = Some(
+                    stats
+                        .min_lifetime_blocks
+                        .map_or(lifetime, |l| l.min(lifetime)),
+                );
+            }
+            stats.last_close_height = stats.last_close_height.max(Some(close_height));
+        }
+    }
+
+    let (mut forward_days, forwards_index, offered_forwards) = {
+        let history_cache = plugin.state().history_cache.lock();
+        (
+            history_cache.forward_days.clone(),
+            history_cache.forwards_index,
+            history_cache.offered_forwards.clone(),
+        )
+    };
+    let mut next_forwards_index = forwards_index;
+    let mut still_offered = BTreeSet::new();
+    let mut start = offered_forwards
+        .first()
+        .map_or(forwards_index, |i| forwards_index.min(*i));
+    loop {
+        let forwards = rpc
+            .call_typed(&ListforwardsRequest {
+                in_channel: None,
+                out_channel: None,
+                status: None,
+                index: Some(ListforwardsIndex::CREATED),
+                start: Some(start),
+                limit: Some(FORWARDS_PAGE_SIZE),
+            })
+            .await?
+            .forwards;
+        let page_len = forwards.len();
+        for forward in forwards {
+            start = forward.created_index + 1;
+            next_forwards_index = next_forwards_index.max(start);
+            if forward.created_index < forwards_index
+                && !offered_forwards.contains(&forward.created_index)
+            {
+                continue;
+            }
+            let received_s = forward.received_time as u64;
+            if unix_now_s.saturating_sub(received_s) > HISTORY_WINDOW_S {
+                continue;
+            }
+            if let ListforwardsForwardsStatus::OFFERED = forward.status {
+                still_offered.insert(forward.created_index);
+                continue;
+            }
+            let peers = [Some(forward.in_channel), forward.out_channel]
+                .into_iter()
+                .flatten()
+                .filter_map(|scid| scid_peers.get(&scid))
+                .collect::<HashSet<_>>();
+            for peer in peers {
+                let stats = forward_days
+                    .entry(*peer)
+                    .or_default()
+                    .entry(received_s / DAY_S)
+                    .or_default();
+                match forward.status {
+                    ListforwardsForwardsStatus::SETTLED => {
+                        stats.settled += 1;
+                        stats.fee_msat += forward.fee_msat.map_or(0, |f| f.msat());
+                        stats.volume_msat += forward.out_msat.map_or(0, |o| o.msat());
+                    }
+                    ListforwardsForwardsStatus::FAILED
+                    | ListforwardsForwardsStatus::LOCAL_FAILED => stats.failed += 1,
+                    ListforwardsForwardsStatus::OFFERED => (),
+                }
+            }
+        }
+        if page_len < FORWARDS_PAGE_SIZE as usize {
+            break;
+        }
+    }
+
+    let oldest_day = unix_now_s.saturating_sub(HISTORY_WINDOW_S) / DAY_S;
+    forward_days.retain(|_, days| {
+        days.retain(|day, _| *day >= oldest_day);
+        !days.is_empty()
+    });
+
+    let mut peers: HashMap<PublicKey, PeerHistory> = HashMap::new();
+    for (peer, days) in &forward_days {
+        let history = peers.entry(*peer).or_default();
+        let mut failed = 0;
+        for stats in days.values() {
+            history.forwards_30d += stats.settled;
+            history.fee_earned_msat_30d += stats.fee_msat;
+            history.volume_msat_30d += stats.volume_msat;
+            failed += stats.failed;
+        }
+        let total = history.forwards_30d + failed;
+        history.failed_forward_ratio = (failed * 100).checked_div(total).unwrap_or(0);
+    }
+    for (peer, stats) in close_stats {
+        let history = peers.entry(peer).or_default();
+        history.closed_count = stats.closed;
+        history.force_closed_by_peer = stats.force_closed_by_peer;
+        history.mutual_closes = stats.mutual;
+        history.min_channel_lifetime_blocks = stats.min_lifetime_blocks.unwrap_or(u64::MAX);
+        history.last_close_age_blocks = stats
+            .last_close_height
+            .map_or(u64::MAX, |h| u64::from(blockheight.saturating_sub(h)));
+    }
+
+    let mut history_cache = plugin.state().history_cache.lock();
+    if history_cache.forwards_index != forwards_index {
+        log::debug!("refresh_history_cache: forwards were already refreshed by another run");
+        return Ok(());
+    }
+    history_cache.peers = peers;
+    history_cache.closes = closes;
+    history_cache.forward_days = forward_days;
+    history_cache.forwards_index = next_forwards_index;
+    history_cache.offered_forwards = still_offered;
+    history_cache.age = unix_now_s;
+
+    log::debug!(
+        "refresh_history_cache done in: {}ms",
+        now.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+pub async fn sample_uptime(plugin: Plugin<PluginState>, ping: bool) -> Result<(), anyhow::Error> {
+    let now = Instant::now();
+    log::debug!("Starting sample_uptime task");
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let rpc_path =
+        Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
+    let mut rpc = ClnRpc::new(&rpc_path).await?;
+
+    let peers = rpc
+        .call_typed(&ListpeersRequest {
+            id: None,
+            level: None,
+        })
+        .await?
+        .peers;
+    let connected = peers
+        .iter()
+        .filter(|p| p.connected)
+        .map(|p| p.id)
+        .collect::<HashSet<_>>();
+
+    {
+        let mut uptime_cache = plugin.state().uptime_cache.lock();
+        for peer in &peers {
+            uptime_cache.entry(peer.id).or_default();
+        }
+        for (pubkey, uptime) in uptime_cache.iter_mut() {
+            record_sample(uptime, unix_now_s, connected.contains(pubkey));
+            prune_uptime(uptime, unix_now_s);
+        }
+        uptime_cache
+            .retain(|_, u| u.hours.iter().any(|h| h.online > 0) || !u.disconnects.is_empty());
+    }
+
+    // no latency samples on CLN versions where ping can get stuck
+    if ping
+        && plugin
+            .state()
+            .config
+            .lock()
+            .check_capability(Capability::Ping)
+            .is_ok()
+    {
+        let ping_length = plugin.state().config.lock().ping_length;
+        let channel_peers = rpc
+            .call_typed(&ListpeerchannelsRequest {
+                id: None,
+                short_channel_id: None,
+                channel_id: None,
+            })
+            .await?
+            .channels
+            .into_iter()
+            .filter(|c| is_active_or_opening(&c.state))
+            .map(|c| c.peer_id)
+            .collect::<HashSet<_>>();
+        let semaphore = Arc::new(Semaphore::new(UPTIME_PING_CONCURRENCY));
+        let mut pings = JoinSet::new();
+        for pubkey in connected.intersection(&channel_peers).copied() {
+            let plugin = plugin.clone();
+            let rpc_path = rpc_path.clone();
+            let semaphore = semaphore.clone();
+            pings.spawn(async move {
+                let _permit = semaphore.acquire_owned().await?;
+                sample_latency(&plugin, rpc_path, pubkey, ping_length, unix_now_s).await
+            });
+        }
+        let join_all = async {
+            while let Some(result) = pings.join_next().await {
+                match result {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => log::debug!("sample_uptime: {e}"),
+                    Err(e) => log::debug!("sample_uptime: ping task failed: {e}"),
+                }
+            }
+        };
+        if timeout(Duration::from_millis(UPTIME_PING_DEADLINE_MS), join_all)
+            .await
+            .is_err()
+        {
+            log::debug!(
+                "sample_uptime: pings took longer than {UPTIME_PING_DEADLINE_MS}ms, \
+                skipping {} peers",
+                pings.len()
+            );
+        }
+    }
+
+    save_uptime_cache(&plugin).await?;
+
+    log::debug!("sample_uptime done in: {}ms", now.elapsed().as_millis());
+    Ok(())
+}
+
+async fn sample_latency(
+    plugin: &Plugin<PluginState>,
+    rpc_path: PathBuf,
+    pubkey: PublicKey,
+    ping_length: u16,
+    unix_now_s: u64,
+) -> Result<(), anyhow::Error> {
+    // A timed out call leaves an unanswered request on the socket, so every
+    // ping gets its own connection
+    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    let ping_start = Instant::now();
+    let ms = match timeout(
+        Duration::from_millis(UPTIME_PING_TIMEOUT_MS),
+        rpc.call_typed(&PingRequest {
+            len: Some(ping_length),
+            pongbytes: Some(ping_length),
+            id: pubkey,
+        }),
+    )
+    .await
+    {
+        Ok(Ok(_)) => u64::try_from(ping_start.elapsed().as_millis())?,
+        Ok(Err(e)) => {
+            log::debug!("sample_uptime: ping to {pubkey} failed: {e}");
+            return Ok(());
+        }
+        Err(_) => UPTIME_PING_TIMEOUT_MS,
+    };
+    if let Some(uptime) = plugin.state().uptime_cache.lock().get_mut(&pubkey) {
+        uptime.latencies.push(PingSample {
+            timestamp: unix_now_s,
+            ms,
+        });
+    }
+    Ok(())
+}
+
+fn record_sample(uptime: &mut PeerUptime, unix_now_s: u64, connected: bool) {
+    let hour = unix_now_s / 3600;
+    match uptime.hours.last_mut() {
+        Some(h) if h.hour == hour => {
+            h.online += u64::from(connected);
+            h.total += 1;
+        }
+        _ => uptime.hours.push(UptimeHour {
+            hour,
+            online: u64::from(connected),
+            total: 1,
+        }),
+    }
+    uptime.connected = connected;
+}
+
+// Connects and disconnects are not samples, counting them in the hours would
+// weigh peers that reconnect often differently
+pub fn record_connection(uptime: &mut PeerUptime, unix_now_s: u64, connected: bool) {
+    if !connected {
+        uptime.disconnects.push(unix_now_s);
+    }
+    uptime.connected = connected;
+}
+
+fn prune_uptime(uptime: &mut PeerUptime, unix_now_s: u64) {
+    uptime
+        .hours
+        .retain(|h| unix_now_s.saturating_sub(h.hour * 3600) <= UPTIME_WINDOW_S);
+    uptime
+        .disconnects
+        .retain(|d| unix_now_s.saturating_sub(*d) <= DISCONNECT_WINDOW_S);
+    uptime
+        .latencies
+        .retain(|l| unix_now_s.saturating_sub(l.timestamp) <= UPTIME_WINDOW_S);
+}
+
+pub fn apply_uptime_history(history: &mut PeerHistory, uptime: &PeerUptime) {
+    let unix_now_s = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let (online, total) = uptime
+        .hours
+        .iter()
+        .filter(|h| unix_now_s.saturating_sub(h.hour * 3600) <= UPTIME_WINDOW_S)
+        .fold((0, 0), |(o, t), h| (o + h.online, t + h.total));
+    history.uptime_ratio_7d = (online * 100).checked_div(total).unwrap_or(u64::MAX);
+    history.disconnects_24h = uptime
+        .disconnects
+        .iter()
+        .filter(|d| unix_now_s.saturating_sub(**d) <= DISCONNECT_WINDOW_S)
+        .count() as u64;
+    let latencies = uptime
+        .latencies
+        .iter()
+        .filter(|l| unix_now_s.saturating_sub(l.timestamp) <= UPTIME_WINDOW_S)
+        .map(|l| l.ms)
+        .collect::<Vec<_>>();
+    history.latency_p50_ms = if latencies.is_empty() {
+        u64::MAX
+    } else {
+        median(latencies)
+    };
+}
+
+pub async fn save_uptime_cache(plugin: &Plugin<PluginState>) -> Result<(), anyhow::Error> {
+    let content = serde_json::to_string(&*plugin.state().uptime_cache.lock())?;
+    let file_path = Path::new(&plugin.configuration().lightning_dir)
+        .join(PLUGIN_NAME)
+        .join(UPTIME_FILE);
+    let tmp_path = file_path.with_extension("json.tmp");
+    fs::write(&tmp_path, content).await?;
+    fs::rename(&tmp_path, &file_path).await?;
+    Ok(())
+}
+
+pub async fn save_peerdata_cache(plugin: &Plugin<PluginState>) -> Result<(), anyhow::Error> {
+    let (mempool_url, ext_command) = {
+        let config = plugin.state().config.lock();
+        (config.mempool_url.clone(), config.ext_command.clone())
+    };
+    let cache_file = CacheFile {
+        version: CACHE_FILE_VERSION,
+        saved_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        mempool_url,
+        ext_command,
+        peerdata: plugin.state().peerdata_cache.lock().clone(),
+        aliases: plugin.state().alias_cache.lock().clone(),
+    };
+    let content = serde_json::to_string(&cache_file)?;
+    let file_path = Path::new(&plugin.configuration().lightning_dir)
+        .join(PLUGIN_NAME)
+        .join(CACHE_FILE);
+    let tmp_path = file_path.with_extension("json.tmp");
+    fs::write(&tmp_path, content).await?;
+    fs::rename(&tmp_path, &file_path).await?;
+    log::debug!(
+        "Saved cached data of {} peers",
+        cache_file.peerdata.peer_count()
+    );
+    Ok(())
+}
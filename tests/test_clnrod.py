@@ -1,6 +1,12 @@
 #!/usr/bin/python
 
+import json
 import logging
+import os
+import re
+import socket
+import threading
+from http.server import BaseHTTPRequestHandler, HTTPServer
 
 import pytest
 from pyln.client import RpcError
@@ -312,6 +318,657 @@ def test_clnrod_custom_gossip_v2(node_factory, bitcoind, get_plugin):  # noqa: F
     )
 
 
+def test_clnrod_gossip_stats(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(
+        2, wait_for_announce=True, opts=[{"plugin": get_plugin}, {}]
+    )
+    wait_for(
+        lambda: "last_timestamp" in l1.rpc.call("listnodes", [l2.info["id"]])["nodes"][0]
+    )
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_node_age_blocks >= 5 && cln_node_age_blocks < 10 && cln_last_update_age_s < 600",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_median_channel_sat == 1000000 && cln_min_channel_sat == 1000000 && cln_max_channel_sat == 1000000 && cln_active_channel_ratio == 100",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            'feature(anchors) == true && feature(22) == true && feature(2000) == false && cln_impl == "cln"',
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    with pytest.raises(RpcError, match="Error parsing custom_rule"):
+        l1.rpc.setconfig("clnrod-customrule", "feature(anchor) == true")
+
+
+def test_clnrod_fee_stats(node_factory, get_plugin):  # noqa: F811
+    l1, l2, l3 = node_factory.line_graph(
+        3, wait_for_announce=True, opts=[{"plugin": get_plugin}, {}, {}]
+    )
+    l2.rpc.setchannel(l1.info["id"], feebase=0, feeppm=100)
+    l2.rpc.setchannel(l3.info["id"], feebase=2000, feeppm=500)
+    wait_for(
+        lambda: sorted(
+            (c["base_fee_millisatoshi"], c["fee_per_millionth"])
+            for c in l1.rpc.listchannels(source=l2.info["id"])["channels"]
+        )
+        == [(0, 100), (2000, 500)]
+    )
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_median_fee_ppm == 300 && cln_max_fee_ppm == 500 && cln_median_base_fee_msat == 1000 && cln_zero_base_fee_ratio == 50",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_graph_distance(node_factory, get_plugin):  # noqa: F811
+    l1, l2, l3 = node_factory.line_graph(
+        3, wait_for_announce=True, opts=[{"plugin": get_plugin}, {}, {}]
+    )
+    wait_for(lambda: len(l1.rpc.listchannels()["channels"]) == 4)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l3.info["id"],
+            True,
+            1_000_000,
+            "cln_hops_from_us == 2 && cln_common_peers == 1",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_hops_from_us == 1 && cln_common_peers == 0",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_centrality_rank == 1 && cln_channels_percentile > 0 && cln_capacity_percentile > 0",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_alias_impersonation(node_factory, get_plugin):  # noqa: F811
+    l1, l2, l3 = node_factory.get_nodes(
+        3,
+        opts=[{"plugin": get_plugin}, {"alias": "BIGNODE"}, {"alias": "B1GN0DE"}],
+    )
+    node_factory.join_nodes([l1, l2], fundamount=10_000_000, wait_for_announce=True)
+    node_factory.join_nodes([l2, l3], fundamount=1_000_000, wait_for_announce=True)
+    wait_for(
+        lambda: l1.rpc.listnodes(l3.info["id"])["nodes"]
+        and "alias" in l1.rpc.listnodes(l3.info["id"])["nodes"][0]
+    )
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l3.info["id"], True, 1_000_000, "cln_alias_impersonation == false"],
+    )
+    assert not result["custom_rule_result"]
+    assert l2.info["id"] in result["reject_reason"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "cln_alias_impersonation == false"],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_clusters(node_factory, get_plugin):  # noqa: F811
+    l1, l2, l3 = node_factory.line_graph(
+        3,
+        wait_for_announce=True,
+        opts=[{"plugin": get_plugin}, {"alias": "CLUSTERNODE1"}, {"alias": "clusternode-2"}],
+    )
+    wait_for(lambda: len(l1.rpc.listnodes()["nodes"]) == 3)
+
+    result = l1.rpc.call("clnrod-listclusters")
+    cluster = only_one(result["clusters"])
+    assert cluster["size"] == 2
+    assert cluster["channels_with_us"] == 1
+    assert cluster["signals"] == ["alias clusternode"]
+    assert sorted(m["id"] for m in cluster["members"]) == sorted(
+        [l2.info["id"], l3.info["id"]]
+    )
+
+    result = l1.rpc.call("clnrod-listclusters", [l1.info["id"]])
+    assert result["clusters"] == []
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l3.info["id"],
+            True,
+            1_000_000,
+            "cluster_size == 2 && cluster_channels_with_us == 1",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_ping_stats(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(
+        2, opts=[{"plugin": get_plugin, "clnrod-pingcount": 4}, {}]
+    )
+
+    result = l1.rpc.call("clnrod-testping", [l2.info["id"]])
+    assert result["sent"] == 4
+    assert result["received"] == 4
+    assert result["loss_pct"] == 0
+    assert result["min"] <= result["median"] <= result["max"]
+    assert result["jitter"] <= result["max"]
+
+    with pytest.raises(RpcError, match="count must be <=20"):
+        l1.rpc.call("clnrod-testping", [l2.info["id"], 21])
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "ping_loss_pct == 0 && ping_median < 5000 && ping_max < 5000 && ping_jitter < 5000",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_cache_sources(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "cln_channel_count >= 1"],
+    )
+    assert result["custom_rule_result"]
+
+    # the cached gossip data must not stop the ping from being collected
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "cln_channel_count >= 1 && ping < 5000"],
+    )
+    assert result["custom_rule_result"]
+    assert l1.daemon.is_in_log("collect_data: gossip cache hit")
+
+
+def test_clnrod_prefetch(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(
+        2,
+        opts=[
+            {"plugin": get_plugin, "clnrod-customrule": "cln_node_capacity_sat >= 0"},
+            {},
+        ],
+    )
+    l3 = node_factory.get_node()
+    l3.rpc.connect(l1.info["id"], "localhost", l1.port)
+    l1.daemon.wait_for_log(r"prefetch_data: {} done".format(l3.info["id"]))
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l3.info["id"], True, 1_000_000, "cln_node_capacity_sat >= 0"],
+    )
+    assert result["custom_rule_result"]
+    assert l1.daemon.is_in_log("collect_data: gossip cache hit")
+
+
+def test_clnrod_cache_persisted(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "cln_channel_count >= 1"],
+    )
+    assert result["custom_rule_result"]
+
+    l1.restart()
+    assert os.path.exists(os.path.join(l1.info["lightning-dir"], "clnrod", "cache.json"))
+    l1.daemon.wait_for_log("Loaded cached data of 1 peers")
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "cln_channel_count >= 1"],
+    )
+    assert result["custom_rule_result"]
+    assert l1.daemon.is_in_log("collect_data: gossip cache hit")
+
+    # data from other sources is not loaded
+    l1.stop()
+    l1.daemon.opts["clnrod-mempool-url"] = "http://127.0.0.1:1"
+    l1.start()
+    l1.daemon.wait_for_log(
+        "cache.json was saved with a different clnrod-mempool-url or clnrod-ext-command"
+    )
+
+    # files with another format version are ignored
+    cache_file = os.path.join(l1.info["lightning-dir"], "clnrod", "cache.json")
+    l1.stop()
+    with open(cache_file, "w") as f:
+        json.dump({"version": 0}, f)
+    l1.start()
+    l1.daemon.wait_for_log("cache.json has an old format")
+
+
+def test_clnrod_own_node(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+    l1.fundwallet(1_000_000)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "self_channel_count == 1 && self_pending_opens == 0 && self_onchain_sat >= 1000000 && self_inbound_ratio == 0 && self_total_capacity_sat == 1000000",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_close_history(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+    l1.rpc.close(l2.info["id"])
+    bitcoind.generate_block(1, wait_for_mempool=1)
+    wait_for(lambda: len(l1.rpc.listclosedchannels()["closedchannels"]) == 1)
+    sync_blockheight(bitcoind, [l1])
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "hist_closed_count == 1 && hist_mutual_closes == 1 && hist_force_closed_by_peer == 0 && hist_last_close_age_blocks < 10",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_force_close_history(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+    l1.rpc.disconnect(l2.info["id"], force=True)
+    l2.rpc.close(l1.info["id"], 1)
+    bitcoind.generate_block(1, wait_for_mempool=1)
+    bitcoind.generate_block(100)
+    wait_for(lambda: len(l1.rpc.listclosedchannels()["closedchannels"]) == 1)
+    sync_blockheight(bitcoind, [l1])
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "hist_closed_count == 1 && hist_mutual_closes == 0 && hist_force_closed_by_peer == 1",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_forward_history(node_factory, get_plugin):  # noqa: F811
+    l1, l2, l3 = node_factory.line_graph(
+        3, wait_for_announce=True, opts=[{}, {"plugin": get_plugin}, {}]
+    )
+    inv = l3.rpc.invoice(100_000, "paid", "paid")
+    l1.rpc.pay(inv["bolt11"])
+
+    inv = l3.rpc.invoice(100_000, "deleted", "deleted")
+    l3.rpc.delinvoice("deleted", "unpaid")
+    with pytest.raises(RpcError):
+        l1.rpc.pay(inv["bolt11"])
+    wait_for(
+        lambda: sorted(f["status"] for f in l2.rpc.listforwards()["forwards"])
+        == ["failed", "settled"]
+    )
+
+    result = l2.rpc.call(
+        "clnrod-testrule",
+        [
+            l1.info["id"],
+            True,
+            1_000_000,
+            "hist_forwards_30d == 1 && hist_volume_msat_30d == 100000 && hist_fee_earned_msat_30d > 0 && hist_failed_forward_ratio == 50",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_uptime_history(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+    l2.rpc.disconnect(l1.info["id"], force=True)
+    wait_for(lambda: not only_one(l1.rpc.listpeers(l2.info["id"])["peers"])["connected"])
+    l2.rpc.connect(l1.info["id"], "localhost", l1.port)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "hist_disconnects_24h == 1 && hist_uptime_ratio_7d == 18446744073709551615",
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    l1.restart()
+    assert os.path.exists(os.path.join(l1.info["lightning-dir"], "clnrod", "uptime.json"))
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, "hist_disconnects_24h >= 1"],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_channel_params(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "clnrod-customrule": "push_msat == 0 && to_self_delay < 2016 && max_accepted_htlcs > 0 && feerate_per_kw > 0 && chan_type_anchors == true && chan_type_bit(12) == true && chan_type_zeroconf == false",
+                "clnrod-denymessage": "No thanks",
+            },
+            {},
+        ],
+    )
+    l2.fundwallet(10_000_000)
+
+    with pytest.raises(RpcError, match="No thanks"):
+        l2.rpc.fundchannel(
+            l1.info["id"] + "@localhost:" + str(l1.port),
+            1_000_000,
+            push_msat=100_000,
+        )
+    l1.daemon.wait_for_log(r"Offending comparisons: `push_msat == 0 -> actual: 100000`")
+
+    l2.rpc.fundchannel(
+        l1.info["id"] + "@localhost:" + str(l1.port),
+        1_000_000,
+    )
+    wait_for(lambda: len(l1.rpc.listpeerchannels(l2.info["id"])["channels"]) > 0)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        {
+            "pubkey": l2.info["id"],
+            "public": True,
+            "their_funding_sat": 1_000_000,
+            "rule": "push_msat == 0 && to_self_delay == 144 && chan_type_anchors == true && chan_type_zeroconf == false",
+            "channel_params": {"to_self_delay": 144, "channel_type": [12, 22]},
+        },
+    )
+    assert result["custom_rule_result"]
+
+    for channel_type in ([12, 22, 81], [12, 22, 180]):
+        result = l1.rpc.call(
+            "clnrod-testrule",
+            {
+                "pubkey": l2.info["id"],
+                "public": True,
+                "their_funding_sat": 1_000_000,
+                "rule": "chan_type_taproot == true",
+                "channel_params": {"channel_type": channel_type},
+            },
+        )
+        assert result["custom_rule_result"]
+
+    with pytest.raises(RpcError, match="channel_params: not a valid object"):
+        l1.rpc.call(
+            "clnrod-testrule",
+            [l2.info["id"], True, 1_000_000, "to_self_delay > 0", {"to_self_dlay": 1}],
+        )
+
+
+def test_clnrod_unknown_policy(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "clnrod-customrule": "cln_channel_count >= 0",
+                "clnrod-denymessage": "No thanks",
+            },
+            {},
+        ],
+    )
+    l2.fundwallet(10_000_000)
+
+    with pytest.raises(RpcError, match="No thanks"):
+        l2.rpc.fundchannel(
+            l1.info["id"] + "@localhost:" + str(l1.port),
+            1_000_000,
+        )
+    l1.daemon.wait_for_log(r"is unknown to gossip, unknown policy: reject")
+    assert not l1.daemon.is_in_log(r"COLLECT_DATA ERROR")
+
+    with pytest.raises(RpcError, match="could not parse UnknownPolicy"):
+        l1.rpc.setconfig("clnrod-unknown-policy", "test")
+    l1.rpc.setconfig("clnrod-unknown-policy", "evaluate")
+    l1.rpc.setconfig("clnrod-customrule", 'cln_node_known == false && cln_impl == "unknown"')
+
+    l2.rpc.fundchannel(
+        l1.info["id"] + "@localhost:" + str(l1.port),
+        1_000_000,
+    )
+    wait_for(lambda: len(l1.rpc.listpeerchannels(l2.info["id"])["channels"]) > 0)
+
+
+def test_clnrod_connection_cidr(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {
+                "plugin": get_plugin,
+                "clnrod-customrule": 'conn_is_tor == false && conn_ip_version == 4 && conn_is_private_ip == true && conn_in_cidr("file:badnets.txt") == false',
+                "clnrod-denymessage": "No thanks",
+            },
+            {},
+        ],
+    )
+    with open(os.path.join(l1.info["lightning-dir"], "clnrod", "badnets.txt"), "w") as f:
+        f.write("# localhost\n127.0.0.0/8\n")
+    l2.fundwallet(10_000_000)
+
+    with pytest.raises(RpcError, match="No thanks"):
+        l2.rpc.fundchannel(
+            l1.info["id"] + "@localhost:" + str(l1.port),
+            1_000_000,
+        )
+    l1.daemon.wait_for_log(r"conn_in_cidr")
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 1_000_000, 'conn_in_cidr("10.0.0.0/8, ::1") == false'],
+    )
+    assert result["custom_rule_result"]
+
+    with open(os.path.join(l1.info["lightning-dir"], "clnrod", "badnets.txt"), "w") as f:
+        f.write("10.0.0.0/8\n")
+    l2.rpc.fundchannel(
+        l1.info["id"] + "@localhost:" + str(l1.port),
+        1_000_000,
+    )
+    wait_for(lambda: len(l1.rpc.listpeerchannels(l2.info["id"])["channels"]) > 0)
+
+
+def test_clnrod_ext_command(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+
+    ext_command = os.path.join(l1.info["lightning-dir"], "ext.py")
+    with open(ext_command, "w") as f:
+        f.write(
+            "#!/usr/bin/env python3\n"
+            "import json, sys\n"
+            "data = json.load(sys.stdin)\n"
+            "print(json.dumps({'score': data['openinginfo']['their_funding_sat'] // 1000,"
+            " 'ext_flagged': False, 'ext_prefix': data['pubkey'][:2]}))\n"
+        )
+    os.chmod(ext_command, 0o755)
+    l1.rpc.setconfig("clnrod-ext-command", ext_command)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            'ext_score == 1000 && ext_flagged == false && ext_prefix == "'
+            + l2.info["id"][:2]
+            + '"',
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    # cached results are only used for the same channel open
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [l2.info["id"], True, 2_000_000, "ext_score == 2000"],
+    )
+    assert result["custom_rule_result"]
+
+    with open(ext_command, "w") as f:
+        f.write("#!/usr/bin/env python3\nimport time\ntime.sleep(10)\n")
+    l1.rpc.setconfig("clnrod-ext-timeout", 1)
+    l1.rpc.setconfig("clnrod-ext-command", ext_command)
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            'ext_score != 1 || ext_prefix != "02"',
+        ],
+    )
+    assert not result["custom_rule_result"]
+    assert "ext_score != 1 -> actual: unknown" in result["reject_reason"]
+    l1.daemon.wait_for_log(r"ext command timed out")
+
+    with open(ext_command, "w") as f:
+        f.write("#!/usr/bin/env python3\nimport sys\nsys.exit(1)\n")
+    l1.rpc.setconfig("clnrod-ext-command", ext_command)
+    l1.rpc.setconfig("clnrod-customrule", "ext_flagged != 1")
+    l1.rpc.setconfig("clnrod-denymessage", "No thanks")
+
+    l2.fundwallet(10_000_000)
+    with pytest.raises(RpcError, match="No thanks"):
+        l2.rpc.fundchannel(l1.info["id"] + "@localhost:" + str(l1.port), 1_000_000)
+    l1.daemon.wait_for_log(r"Offending comparisons: `ext_flagged != 1 -> actual: unknown`")
+
+
+def test_clnrod_geo(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.line_graph(2, opts=[{"plugin": get_plugin}, {}])
+    l3 = node_factory.get_node()
+    l3.rpc.connect(l1.info["id"], "localhost", l1.port)
+
+    with pytest.raises(RpcError, match="ip2asn.tsv is missing"):
+        l1.rpc.call(
+            "clnrod-testrule",
+            [l3.info["id"], True, 1_000_000, "geo_asn == 0"],
+        )
+
+    with open(os.path.join(l1.info["lightning-dir"], "clnrod", "ip2asn.tsv"), "w") as f:
+        f.write("1.0.0.0\t1.0.0.255\t13335\tUS\tCLOUDFLARENET\n")
+        f.write("127.0.0.0\t127.255.255.255\t64512\tZZ\tLOCALHOST\n")
+        f.write("not an ip\t127.255.255.255\t64512\tZZ\tBROKEN\n")
+    result = l1.rpc.call("clnrod-reload")
+    assert result["geo_ranges"] == 2
+    l1.daemon.wait_for_log("Skipping ip2asn.tsv, line 3")
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l3.info["id"],
+            True,
+            1_000_000,
+            'geo_asn == 64512 && geo_country == "ZZ" && geo_asn_channel_count == 1',
+        ],
+    )
+    assert result["custom_rule_result"]
+
+
+def test_clnrod_reachability(node_factory, get_plugin):  # noqa: F811
+    # the open port on loopback must not be probed
+    listener = socket.socket()
+    listener.bind(("127.0.0.1", 0))
+    listener.listen()
+    open_port = listener.getsockname()[1]
+
+    l1, l2 = node_factory.line_graph(
+        2,
+        wait_for_announce=True,
+        opts=[
+            {"plugin": get_plugin},
+            {
+                "announce-addr": [
+                    "1.2.3.4:9735",
+                    "127.0.0.1:" + str(open_port),
+                ]
+            },
+        ],
+    )
+    wait_for(
+        lambda: len(
+            only_one(l1.rpc.listnodes(l2.info["id"])["nodes"]).get("addresses", [])
+        )
+        == 2
+    )
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            l2.info["id"],
+            True,
+            1_000_000,
+            "cln_clearnet_reachable == false && cln_reachable_address_count == 0",
+        ],
+    )
+    assert result["custom_rule_result"]
+    l1.daemon.wait_for_log(
+        r"reachability probe 127.0.0.1:" + str(open_port) + ": no public address"
+    )
+    listener.close()
+
+
 def test_clnrod_allowlist(node_factory, get_plugin):  # noqa: F811
     l1, l2 = node_factory.get_nodes(
         2,
@@ -426,6 +1083,28 @@ def test_clnrod_denylist(node_factory, bitcoind, get_plugin):  # noqa: F811
         )
 
 
+def test_clnrod_version_gating(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(2, opts=[{"plugin": get_plugin}, {}])
+    l2.rpc.connect(l1.info["id"], "localhost", l1.port)
+    version = re.match(r"v?(\d+)\.(\d+)(?:\.(\d+))?", l1.rpc.getinfo()["version"])
+    major, minor, patch = (int(v or 0) for v in version.groups())
+    parsed = f"v{major}.{minor:02d}" + (f".{patch}" if patch else "")
+    gated = (major, minor) < (25, 9)
+    l1.daemon.wait_for_log(r"CLN version: " + parsed)
+
+    if gated:
+        with pytest.raises(RpcError, match=r"ping is disabled on CLN " + parsed):
+            l1.rpc.setconfig("clnrod-customrule", "ping < 1000")
+        with pytest.raises(RpcError, match=r"ping is disabled on CLN " + parsed):
+            l1.rpc.call("clnrod-testping", [l2.info["id"]])
+        # only the ping variables are refused, not names that contain ping
+        l1.rpc.setconfig("clnrod-customrule", "ext_mapping == 1")
+    else:
+        l1.rpc.setconfig("clnrod-customrule", "ping < 1000")
+        result = l1.rpc.call("clnrod-testping", [l2.info["id"], 1])
+        assert result["sent"] == 1
+
+
 def test_options(node_factory, get_plugin):  # noqa: F811
     l1 = node_factory.get_node(options={"plugin": get_plugin})
 
@@ -475,6 +1154,22 @@ def test_options(node_factory, get_plugin):  # noqa: F811
         l1.rpc.setconfig("clnrod-pinglength", 99999)
     l1.rpc.setconfig("clnrod-pinglength", 9999)
 
+    with pytest.raises(RpcError, match="clnrod-pingcount must be greater than 0"):
+        l1.rpc.setconfig("clnrod-pingcount", 0)
+    with pytest.raises(RpcError, match="clnrod-pingcount must not be greater than 20"):
+        l1.rpc.setconfig("clnrod-pingcount", 21)
+    l1.rpc.setconfig("clnrod-pingcount", 5)
+    with pytest.raises(RpcError, match="clnrod-pingtimeout out of valid range"):
+        l1.rpc.setconfig("clnrod-pingtimeout", 99999)
+    l1.rpc.setconfig("clnrod-pingtimeout", 2000)
+
+    with pytest.raises(RpcError, match="clnrod-cache-ttl-amboss out of valid range"):
+        l1.rpc.setconfig("clnrod-cache-ttl-amboss", -1)
+    l1.rpc.setconfig("clnrod-cache-ttl-amboss", 0)
+    with pytest.raises(RpcError, match="clnrod-cache-size must be greater than 0"):
+        l1.rpc.setconfig("clnrod-cache-size", 0)
+    l1.rpc.setconfig("clnrod-cache-size", 10)
+
     with pytest.raises(RpcError, match="could not parse NotifyVerbosity"):
         l1.rpc.setconfig("clnrod-notify-verbosity", "test")
     l1.rpc.setconfig("clnrod-notify-verbosity", "accepted")
@@ -720,3 +1415,75 @@ def test_external_datasources(node_factory, get_plugin):  # noqa: F811
             "oneml_channelcount > 0 && amboss_has_nostr == true",
         ],
     )
+
+
+def test_mempool_datasource(node_factory, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(2, opts=[{"plugin": get_plugin}, {}])
+    known_id = l2.info["id"]
+
+    class MempoolHandler(BaseHTTPRequestHandler):
+        def do_GET(self):
+            if self.path == f"/api/v1/lightning/nodes/{known_id}":
+                body = json.dumps(
+                    {
+                        "public_key": known_id,
+                        "first_seen": 1600000000,
+                        "active_channel_count": 12,
+                        "capacity": "150000000",
+                        "iso_code": "DE",
+                        "as_number": 24940,
+                    }
+                ).encode()
+                self.send_response(200)
+            else:
+                body = b"This node does not exist, or our node is not seeing it yet"
+                self.send_response(404)
+            self.send_header("Content-Length", str(len(body)))
+            self.end_headers()
+            self.wfile.write(body)
+
+    server = HTTPServer(("127.0.0.1", 0), MempoolHandler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+
+    with pytest.raises(RpcError, match="clnrod-mempool-url is not set"):
+        l1.rpc.call(
+            "clnrod-testrule",
+            [known_id, True, 1_000_000, "mempool_channel_count > 0"],
+        )
+
+    with pytest.raises(RpcError, match="must start with"):
+        l1.rpc.setconfig("clnrod-mempool-url", "127.0.0.1")
+    l1.rpc.setconfig(
+        "clnrod-mempool-url", f"http://127.0.0.1:{server.server_address[1]}/"
+    )
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [
+            known_id,
+            True,
+            1_000_000,
+            'mempool_channel_count >= 12 && mempool_capacity == 150000000 && mempool_country == "de" && mempool_as_number == 24940 && mempool_first_seen < 1700000000',
+        ],
+    )
+    assert result["custom_rule_result"]
+
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [known_id, True, 1_000_000, 'mempool_country != "DE"'],
+    )
+    assert result["reject_reason"] == 'mempool_country != "DE" -> actual: "DE"'
+
+    unknown_id = "0380ef0209ff1b46c38a37cd40f613d1dae3eba481a909459d6c1434a0e56e5d8c"
+    result = l1.rpc.call(
+        "clnrod-testrule",
+        [unknown_id, True, 1_000_000, "mempool_channel_count > 0"],
+    )
+    assert result["reject_reason"] == "mempool_channel_count > 0 -> actual: 0"
+
+    with pytest.raises(RpcError, match="Only `==` and `!=` are supported"):
+        l1.rpc.call(
+            "clnrod-testrule",
+            [known_id, True, 1_000_000, 'mempool_country > "DE"'],
+        )
+    server.shutdown()